@@ -1,8 +1,7 @@
-use super::file::FileInfo;
-use std::cell::RefCell;
 use std::fmt;
 
-#[derive(PartialEq, Copy, Clone)]
+// Spans are ordered by start, then by end.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
 pub struct Span {
     pub start: usize,
     pub end: usize,
@@ -12,6 +11,26 @@ impl Span {
     pub fn new(start: usize, end: usize) -> Self {
         Span { start, end }
     }
+
+    /// Returns the smallest span covering both spans (and anything between
+    /// them).
+    pub fn union(&self, other: &Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+
+    /// Whether `pos` is in the span. The end is exclusive, so an empty span
+    /// contains nothing.
+    pub fn contains(&self, pos: usize) -> bool {
+        self.start <= pos && pos < self.end
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
 }
 
 impl fmt::Debug for Span {
@@ -20,9 +39,145 @@ impl fmt::Debug for Span {
     }
 }
 
-thread_local! {
-    pub static FILE_INFO: RefCell<FileInfo> = RefCell::new(FileInfo {
-        path: String::from("<unspecified>"),
-        text: String::from("")
-    });
+/// The byte offsets at which each line of a source starts, so that offsets
+/// can be turned into lines and columns without rescanning the source.
+///
+/// Lines end at `\n`, `\r\n`, or a `\r` on its own (so `\r\r` ends two
+/// lines).
+#[derive(Clone, Debug)]
+pub struct LineIndex {
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(source: &str) -> Self {
+        let bytes = source.as_bytes();
+        let mut starts = vec![0];
+
+        for (i, &b) in bytes.iter().enumerate() {
+            match b {
+                b'\n' => starts.push(i + 1),
+                // The LF of a CRLF ends the line
+                b'\r' if bytes.get(i + 1) != Some(&b'\n') => starts.push(i + 1),
+                _ => {}
+            }
+        }
+
+        LineIndex { starts }
+    }
+
+    /// Returns the line and column (both starting at 1) of the byte offset
+    /// `pos`. The column is a byte offset into the line, so a tab is a
+    /// single column.
+    pub fn line_col(&self, pos: usize) -> (usize, usize) {
+        let line = self.starts.partition_point(|&start| start <= pos);
+        (line, pos - self.starts[line - 1] + 1)
+    }
+
+    /// Returns the byte offset at which `line` (starting at 1) starts.
+    pub fn line_start(&self, line: usize) -> usize {
+        self.starts[line - 1]
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.starts.len()
+    }
+}
+
+/// Converts the byte offset `pos` in `source` (like the ends of a `Span`)
+/// into the offsets editors tend to use instead: the number of chars before
+/// it, and the number of UTF-16 code units before it. An offset inside a
+/// multi-byte char counts from that char's start, and offsets past the end
+/// count from the end.
+pub fn char_offsets(source: &str, pos: usize) -> (usize, usize) {
+    let mut pos = pos.min(source.len());
+    while !source.is_char_boundary(pos) {
+        pos -= 1;
+    }
+
+    source[..pos].chars().fold((0, 0), |(chars, units), c| {
+        (chars + 1, units + c.len_utf16())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn span_union() {
+        let a = Span::new(2, 5);
+
+        assert_eq!(a.union(&Span::new(3, 4)), a);
+        assert_eq!(a.union(&Span::new(8, 10)), Span::new(2, 10));
+        assert_eq!(Span::new(8, 10).union(&a), Span::new(2, 10));
+        assert_eq!(a.union(&Span::new(0, 0)), Span::new(0, 5));
+    }
+
+    #[test]
+    fn span_contains_and_len() {
+        let span = Span::new(2, 5);
+        assert!(!span.contains(1));
+        assert!(span.contains(2));
+        assert!(span.contains(4));
+        assert!(!span.contains(5));
+        assert_eq!(span.len(), 3);
+        assert!(!span.is_empty());
+
+        let empty = Span::new(3, 3);
+        assert!(!empty.contains(3));
+        assert_eq!(empty.len(), 0);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn get_line_simple() {
+        let src = "first\nsecond\r\nthird";
+        //         012345 6789012 3 45678
+        let index = LineIndex::new(src);
+
+        assert_eq!(index.line_col(3).0, 1);
+        assert_eq!(index.line_col(6).0, 2);
+        assert_eq!(index.line_col(15).0, 3);
+        assert_eq!(index.line_col(451).0, 3);
+    }
+
+    #[test]
+    fn get_line_double_cr() {
+        let src = "first\nsecond\r\rthird";
+        //         012345 6789012 3 45678
+        let index = LineIndex::new(src);
+
+        assert_eq!(index.line_col(15).0, 4);
+    }
+
+    #[test]
+    fn get_line_col() {
+        let src = "first\nse\tcond\r\nthird";
+        let index = LineIndex::new(src);
+
+        assert_eq!(index.line_col(0), (1, 1));
+        assert_eq!(index.line_col(3), (1, 4));
+        assert_eq!(index.line_col(10), (2, 5));
+        assert_eq!(index.line_col(17), (3, 3));
+    }
+
+    #[test]
+    fn char_offsets_of_multibyte_chars() {
+        let src = "caf\u{e9} \u{1f600}!";
+        //         c a f é    😀      !
+        // bytes:  0 1 2 3  5 6       10
+
+        assert_eq!(char_offsets(src, 0), (0, 0));
+        assert_eq!(char_offsets(src, 3), (3, 3));
+        assert_eq!(char_offsets(src, 5), (4, 4));
+        assert_eq!(char_offsets(src, 6), (5, 5));
+        assert_eq!(char_offsets(src, 10), (6, 7));
+        assert_eq!(char_offsets(src, 11), (7, 8));
+
+        // Offsets inside a char count from its start
+        assert_eq!(char_offsets(src, 4), (3, 3));
+        assert_eq!(char_offsets(src, 8), (5, 5));
+        assert_eq!(char_offsets(src, 100), (7, 8));
+    }
 }
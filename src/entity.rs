@@ -0,0 +1,160 @@
+use super::common::Span;
+use super::syntax_error::SyntaxError;
+
+/// Resolves a named entity (given without the surrounding `&` and `;`) that
+/// isn't one of the predefined XML entities.
+pub type EntityResolver = fn(&str) -> Option<String>;
+
+/// Replaces character references (`&amp;`, `&#169;`, `&#xA9;`, ...) in
+/// `text`, which starts at byte offset `start` in the source. References that
+/// can't be resolved are left as-is and reported as warnings.
+pub fn decode_entities(
+    text: &str,
+    start: usize,
+    resolver: Option<EntityResolver>,
+) -> (String, Vec<SyntaxError>) {
+    let mut out = String::with_capacity(text.len());
+    let mut errors = vec![];
+    let mut rest = text;
+
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+
+        let name = match reference_name(rest) {
+            Some(name) => name,
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        let reference = &rest[..name.len() + 2];
+        match resolve(name, resolver) {
+            Some(decoded) => out.push_str(&decoded),
+            None => {
+                let ref_start = start + text.len() - rest.len();
+                let message = if name.starts_with('#') {
+                    format!(r#"invalid character reference "{}""#, reference)
+                } else {
+                    format!(r#"unknown entity "{}""#, reference)
+                };
+                errors.push(SyntaxError::warning(
+                    Span::new(ref_start, ref_start + reference.len()),
+                    message,
+                ));
+                out.push_str(reference);
+            }
+        }
+        rest = &rest[reference.len()..];
+    }
+
+    out.push_str(rest);
+    (out, errors)
+}
+
+// Returns the name of the reference at the start of `text` (which begins with
+// '&'), if there is one: either a name or '#' followed by alphanumerics, and
+// terminated by ';'.
+fn reference_name(text: &str) -> Option<&str> {
+    let body = &text[1..];
+    let end = body.find(';')?;
+    let name = &body[..end];
+
+    let mut chars = name.chars();
+    let valid = match chars.next() {
+        Some(c) if c == '#' || c.is_ascii_alphabetic() => chars.all(|c| c.is_ascii_alphanumeric()),
+        _ => false,
+    };
+
+    if valid {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+fn resolve(name: &str, resolver: Option<EntityResolver>) -> Option<String> {
+    if let Some(number) = name.strip_prefix('#') {
+        let code = match number
+            .strip_prefix('x')
+            .or_else(|| number.strip_prefix('X'))
+        {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return std::char::from_u32(code).map(String::from);
+    }
+
+    let predefined = match name {
+        "amp" => "&",
+        "lt" => "<",
+        "gt" => ">",
+        "quot" => "\"",
+        "apos" => "'",
+        _ => return resolver.and_then(|resolver| resolver(name)),
+    };
+
+    Some(String::from(predefined))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve_custom(name: &str) -> Option<String> {
+        match name {
+            "mycompany" => Some(String::from("ACME")),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn decode_predefined_and_numeric() {
+        let (text, errors) = decode_entities("&lt;b&gt; &#169; &#xA9; &amp;&amp;", 0, None);
+
+        assert_eq!(text, "<b> © © &&");
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn bare_ampersands_are_literal() {
+        let (text, errors) = decode_entities("a && b; &", 0, None);
+
+        assert_eq!(text, "a && b; &");
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn custom_entities_use_resolver() {
+        let (text, errors) = decode_entities("&mycompany; &other;", 10, Some(resolve_custom));
+
+        assert_eq!(text, "ACME &other;");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span, Span::new(22, 29));
+        assert_eq!(errors[0].message, r#"unknown entity "&other;""#);
+        assert!(!errors[0].is_error());
+    }
+
+    #[test]
+    fn invalid_numeric_references_are_literal() {
+        let (text, errors) = decode_entities("&#xZZ; &#1114112; &#65;", 0, None);
+
+        assert_eq!(text, "&#xZZ; &#1114112; A");
+        let messages: Vec<(Span, &str)> = errors
+            .iter()
+            .map(|error| (error.span, error.message.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (Span::new(0, 6), r#"invalid character reference "&#xZZ;""#),
+                (
+                    Span::new(7, 17),
+                    r#"invalid character reference "&#1114112;""#
+                ),
+            ]
+        );
+    }
+}
@@ -1,19 +1,60 @@
-use super::common::FILE_INFO;
+use super::common::LineIndex;
+use std::fmt;
 use std::fs;
+use std::io::{self, Read};
 
-pub struct FileInfo {
-    pub path: String,
-    pub text: String,
+/// A document's source text, along with where it came from, for showing the
+/// parts of it that errors refer to.
+pub struct SourceFile {
+    path: String,
+    text: String,
+    lines: LineIndex,
 }
 
-pub fn read_file(path: &str) -> Result<(), Box<dyn std::error::Error + 'static>> {
-    let text = fs::read_to_string(path)?;
+impl SourceFile {
+    pub fn new<P, S>(path: P, text: S) -> Self
+    where
+        P: Into<String>,
+        S: Into<String>,
+    {
+        let text = text.into();
+        SourceFile {
+            path: path.into(),
+            lines: LineIndex::new(&text),
+            text,
+        }
+    }
 
-    FILE_INFO.with(|info| {
-        let mut info = info.borrow_mut();
-        info.path = String::from(path);
-        info.text = text;
-    });
+    pub fn path(&self) -> &str {
+        &self.path
+    }
 
-    Ok(())
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub(crate) fn lines(&self) -> &LineIndex {
+        &self.lines
+    }
+}
+
+// The text is left out, as it would swamp the errors it's attached to
+impl fmt::Debug for SourceFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SourceFile")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+// Reads the file at `path`, or standard input if `path` is "-".
+pub fn read_file(path: &str) -> Result<SourceFile, Box<dyn std::error::Error + 'static>> {
+    if path == "-" {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text)?;
+        Ok(SourceFile::new("<stdin>", text))
+    } else {
+        let text = fs::read_to_string(path)?;
+        Ok(SourceFile::new(path, text))
+    }
 }
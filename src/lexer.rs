@@ -1,15 +1,110 @@
-use super::common::{Span, FILE_INFO};
+use super::common::Span;
+use super::syntax_error::SyntaxError;
 use std::str::Chars;
 
+const RAW_SECTION_OPEN: &str = "<![RAW[";
+const RAW_SECTION_CLOSE: &str = "]]>";
+
 pub struct Lexer<'a> {
-    input_len: usize,
+    input: &'a str,
     chars: Chars<'a>,
     mode: LexerMode,
-    buffer: Option<Token>,
+    buffer: Option<Token<'a>>,
+    // The name of the most recently opened tag, and whether the last token
+    // was a '<' (so that the next name is a tag name)
+    open_tag_name: Option<&'a str>,
+    after_langle: bool,
+    unknown_char_policy: UnknownCharPolicy,
+    name_chars: NameChars,
+    // When failing fast, where the first anomaly was found
+    fail_fast: bool,
+    halted_at: Option<usize>,
+    // For element-valued attributes: the number of open elements, the depth
+    // (and enclosing tag name) at which each value element being lexed was
+    // opened, and whether the last '<' began a close tag
+    element_attr_values: bool,
+    after_equals: bool,
+    depth: usize,
+    value_elements: Vec<(usize, Option<&'a str>)>,
+    in_close_tag: bool,
+    errors: Vec<SyntaxError>,
+}
+
+/// What to do with a character that can't appear inside a tag, like the `;`
+/// in `<Doc ; title="x">`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UnknownCharPolicy {
+    /// Treat the rest of the input as text, as if the tag had ended, and
+    /// report the character as a warning.
+    #[default]
+    Ignore,
+    /// Report the character as an error and skip it.
+    Error,
+    /// Skip the character, report it as a warning, and carry on lexing the
+    /// tag.
+    Recover,
 }
 
 impl<'a> Lexer<'a> {
-    pub fn peek(&mut self) -> &Token {
+    /// Creates a lexer that starts in `mode` rather than at the start of a
+    /// document. For example, `LexerMode::Outside(2)` resumes lexing the
+    /// content of an element opened with `##>`. Since the lexer hasn't seen
+    /// that element's open tag, nested fences in its content aren't balanced.
+    pub fn with_mode(input: &'a str, mode: LexerMode) -> Self {
+        Lexer {
+            input,
+            chars: input.chars(),
+            mode,
+            buffer: None,
+            open_tag_name: None,
+            after_langle: false,
+            unknown_char_policy: UnknownCharPolicy::default(),
+            name_chars: NameChars::default(),
+            fail_fast: false,
+            halted_at: None,
+            element_attr_values: false,
+            after_equals: false,
+            depth: 0,
+            value_elements: vec![],
+            in_close_tag: false,
+            errors: vec![],
+        }
+    }
+
+    pub fn set_unknown_char_policy(&mut self, policy: UnknownCharPolicy) {
+        self.unknown_char_policy = policy;
+    }
+
+    /// Sets the characters allowed in tag and attribute names.
+    pub fn set_name_chars(&mut self, name_chars: NameChars) {
+        self.name_chars = name_chars;
+    }
+
+    /// Stops at the first anomaly (an unterminated attribute value, orphaned
+    /// hashes, or an unexpected character in a tag) instead of recovering
+    /// from it. The anomaly is recorded as an error, and every token from
+    /// then on is an `Eof` at its start.
+    pub fn set_fail_fast(&mut self, fail_fast: bool) {
+        self.fail_fast = fail_fast;
+    }
+
+    /// Allows attribute values to be elements (see
+    /// `ParseOptions::set_element_attr_values`). The value element's close
+    /// tag returns the lexer to the enclosing tag, rather than to its content.
+    pub fn set_element_attr_values(&mut self, enabled: bool) {
+        self.element_attr_values = enabled;
+    }
+
+    /// Returns the errors found while lexing so far: unterminated attribute
+    /// values, orphaned hashes, unterminated comments and raw sections, and
+    /// characters that can't appear in tags (reported according to the
+    /// `UnknownCharPolicy`, and as warnings unless it's `Error`). The
+    /// parser reports these along with its own errors.
+    pub fn take_errors(&mut self) -> Vec<SyntaxError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    pub fn peek(&mut self) -> &Token<'a> {
         if self.buffer.is_none() {
             self.buffer = Some(self.read_next());
         }
@@ -17,43 +112,116 @@ impl<'a> Lexer<'a> {
         self.buffer.as_ref().unwrap()
     }
 
-    pub fn pop(&mut self) -> Token {
+    /// Returns the tag name (and its span) of the close tag that the next
+    /// token begins, if it is a "</", without consuming anything.
+    pub fn peek_close_tag_name(&mut self) -> Option<(&'a str, Span)> {
+        if self.peek().kind != TokenKind::LAngleSlash {
+            return None;
+        }
+
+        let rest = self
+            .chars
+            .as_str()
+            .trim_start_matches(|c: char| c.is_whitespace() || c == '#');
+        if !rest.starts_with(self.name_chars.start) {
+            return None;
+        }
+        let len = rest
+            .find(|c| !(self.name_chars.rest)(c))
+            .unwrap_or(rest.len());
+        let start = self.input.len() - rest.len();
+
+        Some((&rest[..len], Span::new(start, start + len)))
+    }
+
+    pub fn pop(&mut self) -> Token<'a> {
         match self.buffer {
             Some(_) => self.buffer.take().unwrap(),
             None => self.read_next(),
         }
     }
 
-    fn read_next(&mut self) -> Token {
+    fn read_next(&mut self) -> Token<'a> {
+        if let Some(pos) = self.halted_at {
+            return self.token(TokenKind::Eof, pos, pos);
+        }
+
         match self.mode {
             LexerMode::Inside(hash_count) => self.read_inside(hash_count),
             LexerMode::Outside(hash_count) => self.read_outside(hash_count),
         }
     }
 
-    fn read_inside(&mut self, hash_count: usize) -> Token {
+    fn read_inside(&mut self, hash_count: usize) -> Token<'a> {
         self.skip_whitespace();
 
         let mut start = self.current_pos();
         if self.peek_char().is_none() {
-            return Token::eof(start);
+            return self.token(TokenKind::Eof, start, start);
         }
 
         let kind = match self.chars.next().unwrap() {
             '<' => self.read_langle(hash_count),
             '>' => self.read_rangle(0),
+            '/' if self.peek_char() == Some('>') => {
+                self.chars.next();
+                self.read_slash_rangle()
+            }
             '#' => self.read_hashes(),
             '=' => TokenKind::Equals,
-            '"' => self.read_attr_val(),
-            c if is_name_start(c) => self.read_name(),
-            _ => {
-                self.mode = LexerMode::Outside(0);
+            quote @ ('"' | '\'') => self.read_attr_val(quote),
+            c if (self.name_chars.start)(c) => self.read_name(),
+            c => {
+                let message = format!("unexpected character '{}'", c);
+                if self.fail_fast {
+                    return self.halt(Span::new(start, self.current_pos()), message);
+                }
+
+                let span = Span::new(start, self.current_pos());
+                match self.unknown_char_policy {
+                    UnknownCharPolicy::Ignore => {
+                        self.errors.push(SyntaxError::warning(
+                            span,
+                            format!("{} (the rest of the tag is treated as text)", message),
+                        ));
+                        self.mode = LexerMode::Outside(0);
+                    }
+                    UnknownCharPolicy::Error => self.errors.push(SyntaxError::new(span, message)),
+                    UnknownCharPolicy::Recover => self
+                        .errors
+                        .push(SyntaxError::warning(span, format!("{} (skipped)", message))),
+                }
                 return self.read_next();
             }
         };
 
         let mut end = self.current_pos();
 
+        let anomaly = match kind {
+            TokenKind::UnterminatedAttrVal => Some(String::from("unterminated attribute value")),
+            TokenKind::OrphanHashes => Some(orphaned_hashes_message(end - start)),
+            _ => None,
+        };
+        if let Some(message) = anomaly {
+            if self.fail_fast {
+                return self.halt(Span::new(start, end), message);
+            }
+            // Reported without the opening quote of an unterminated value
+            let from = if kind == TokenKind::UnterminatedAttrVal {
+                start + 1
+            } else {
+                start
+            };
+            self.errors
+                .push(SyntaxError::new(Span::new(from, end), message));
+        }
+
+        if kind == TokenKind::Name && self.after_langle {
+            self.open_tag_name = Some(&self.input[start..end]);
+        }
+        self.after_langle = kind == TokenKind::LAngle;
+        self.after_equals = kind == TokenKind::Equals;
+
         // Adjust start and end positions for quoted values (to exclude
         // quotes).
         if kind == TokenKind::AttrVal {
@@ -63,7 +231,16 @@ impl<'a> Lexer<'a> {
             start += 1;
         }
 
-        Token::new(kind, start, end)
+        self.token(kind, start, end)
+    }
+
+    fn halt<S>(&mut self, span: Span, message: S) -> Token<'a>
+    where
+        S: Into<String>,
+    {
+        self.errors.push(SyntaxError::new(span, message));
+        self.halted_at = Some(span.start);
+        self.token(TokenKind::Eof, span.start, span.start)
     }
 
     fn read_langle(&mut self, hash_count: usize) -> TokenKind {
@@ -74,17 +251,42 @@ impl<'a> Lexer<'a> {
             }
 
             self.mode = LexerMode::Inside(0);
+            self.depth = self.depth.saturating_sub(1);
+            self.in_close_tag = true;
             TokenKind::LAngleSlash
         } else {
+            if self.element_attr_values && self.after_equals {
+                self.value_elements.push((self.depth, self.open_tag_name));
+            }
+            self.depth += 1;
+            self.in_close_tag = false;
             TokenKind::LAngle
         }
     }
 
     fn read_rangle(&mut self, hash_count: usize) -> TokenKind {
-        self.mode = LexerMode::Outside(hash_count);
+        let closes_value = self.in_close_tag
+            && matches!(self.value_elements.last(), Some((depth, _)) if *depth == self.depth);
+
+        if closes_value {
+            let (_, enclosing_tag_name) = self.value_elements.pop().unwrap();
+            self.open_tag_name = enclosing_tag_name;
+            self.mode = LexerMode::Inside(0);
+        } else {
+            self.mode = LexerMode::Outside(hash_count);
+        }
+        self.in_close_tag = false;
         TokenKind::RAngle
     }
 
+    // Ends a self-closing tag, which (like a close tag) ends its element.
+    fn read_slash_rangle(&mut self) -> TokenKind {
+        self.in_close_tag = true;
+        self.depth = self.depth.saturating_sub(1);
+        self.read_rangle(0);
+        TokenKind::SlashRAngle
+    }
+
     fn read_hashes(&mut self) -> TokenKind {
         let hash_count = 1 + self.skip_while(|c| c == '#');
 
@@ -96,7 +298,9 @@ impl<'a> Lexer<'a> {
         }
     }
 
-    fn read_attr_val(&mut self) -> TokenKind {
+    // Reads a value quoted with `quote` (either kind of quote), up to the
+    // matching unescaped quote.
+    fn read_attr_val(&mut self, quote: char) -> TokenKind {
         let mut escape_next = false;
 
         while let Some(c) = self.peek_char() {
@@ -108,7 +312,7 @@ impl<'a> Lexer<'a> {
                 '\\' if !escape_next => {
                     escape_next = true;
                 }
-                '"' if !escape_next => {
+                c if c == quote && !escape_next => {
                     return TokenKind::AttrVal;
                 }
                 _ => {
@@ -121,12 +325,20 @@ impl<'a> Lexer<'a> {
     }
 
     fn read_name(&mut self) -> TokenKind {
-        self.skip_while(is_name_continue);
+        self.skip_while(self.name_chars.rest);
         TokenKind::Name
     }
 
-    fn read_outside(&mut self, hash_count: usize) -> Token {
+    // Hash-fenced content (when `hash_count` is nonzero) ends at the first
+    // "</" followed by exactly `hash_count` hashes, so content fenced with
+    // more hashes can contain weaker fences as text. Fences nest like brackets, though:
+    // each nested open tag with the same name as the fenced element that is
+    // itself closed with `hash_count` hashes (like the inner `<Code#>` in
+    // `<Code#><Code#>x</#Code></#Code>`) must be matched by a closing fence
+    // before the content ends. All nested tags are part of the raw text.
+    fn read_outside(&mut self, hash_count: usize) -> Token<'a> {
         let start = self.current_pos();
+        let mut depth = 0;
 
         let end = loop {
             match self.peek_char() {
@@ -135,16 +347,13 @@ impl<'a> Lexer<'a> {
                         break self.current_pos();
                     }
 
-                    if let Some('/') = self.peek_nth(1) {
-                        if self
-                            .chars
-                            .clone()
-                            .skip(2)
-                            .take(hash_count)
-                            .all(|c| c == '#')
-                        {
+                    if self.at_close_fence(hash_count) {
+                        if depth == 0 {
                             break self.current_pos();
                         }
+                        depth -= 1;
+                    } else if self.at_nested_open_fence(hash_count) {
+                        depth += 1;
                     }
 
                     self.chars.next();
@@ -156,14 +365,112 @@ impl<'a> Lexer<'a> {
             }
         };
 
+        if hash_count == 0 && self.chars.as_str().starts_with("<!--") {
+            // Comments are part of the content, so stay outside of tags
+            return if end > start {
+                self.token(TokenKind::Text, start, end)
+            } else {
+                self.read_comment()
+            };
+        }
+
+        if hash_count == 0 && self.chars.as_str().starts_with(RAW_SECTION_OPEN) {
+            // As are raw sections
+            return if end > start {
+                self.token(TokenKind::Text, start, end)
+            } else {
+                self.read_raw_section()
+            };
+        }
+
         self.mode = LexerMode::Inside(hash_count);
         if end > start {
-            Token::new(TokenKind::Text, start, end)
+            let kind = if hash_count > 0 {
+                TokenKind::RawText
+            } else {
+                TokenKind::Text
+            };
+            self.token(kind, start, end)
         } else {
             self.read_next()
         }
     }
 
+    // Reads a comment, from "<!--" through the next "-->".
+    fn read_comment(&mut self) -> Token<'a> {
+        let start = self.current_pos();
+        let rest = self.chars.as_str();
+
+        let len = match rest[4..].find("-->") {
+            Some(offset) => 4 + offset + 3,
+            None => {
+                self.errors.push(SyntaxError::new(
+                    Span::new(start, start + 4),
+                    "unterminated comment",
+                ));
+                rest.len()
+            }
+        };
+        self.chars = rest[len..].chars();
+
+        self.token(TokenKind::Comment, start, start + len)
+    }
+
+    // Reads a raw section (like `<![RAW[a <b> c]]>`), whose content is
+    // returned as raw text, without its delimiters.
+    fn read_raw_section(&mut self) -> Token<'a> {
+        let start = self.current_pos();
+        let content_start = start + RAW_SECTION_OPEN.len();
+        let rest = &self.chars.as_str()[RAW_SECTION_OPEN.len()..];
+
+        let (content_len, len) = match rest.find(RAW_SECTION_CLOSE) {
+            Some(offset) => (offset, offset + RAW_SECTION_CLOSE.len()),
+            None => {
+                self.errors.push(SyntaxError::new(
+                    Span::new(start, content_start),
+                    "unterminated raw section",
+                ));
+                (rest.len(), rest.len())
+            }
+        };
+        self.chars = rest[len..].chars();
+
+        self.token(
+            TokenKind::RawText,
+            content_start,
+            content_start + content_len,
+        )
+    }
+
+    fn at_close_fence(&self, hash_count: usize) -> bool {
+        let mut chars = self.chars.clone().skip(1);
+        chars.next() == Some('/')
+            && chars.by_ref().take(hash_count).all(|c| c == '#')
+            && chars.next() != Some('#')
+    }
+
+    fn at_nested_open_fence(&self, hash_count: usize) -> bool {
+        let tag_name = match self.open_tag_name {
+            Some(tag_name) => tag_name,
+            None => return false,
+        };
+
+        let rest = match self.chars.as_str()[1..].strip_prefix(tag_name) {
+            Some(rest) => rest,
+            None => return false,
+        };
+        if rest.starts_with(self.name_chars.rest) {
+            return false;
+        }
+
+        let tag = match rest.find('>') {
+            Some(end) => &rest[..end],
+            None => return false,
+        };
+        let hashes = tag.len() - tag.trim_end_matches('#').len();
+        hashes == hash_count
+    }
+
     fn skip_whitespace(&mut self) {
         self.skip_while(is_whitespace);
     }
@@ -185,8 +492,16 @@ impl<'a> Lexer<'a> {
         count
     }
 
+    fn token(&self, kind: TokenKind, start: usize, end: usize) -> Token<'a> {
+        Token {
+            kind,
+            span: Span::new(start, end),
+            text: &self.input[start..end],
+        }
+    }
+
     fn current_pos(&self) -> usize {
-        self.input_len - self.chars.as_str().len()
+        self.input.len() - self.chars.as_str().len()
     }
 
     fn peek_char(&self) -> Option<char> {
@@ -200,44 +515,77 @@ impl<'a> Lexer<'a> {
 
 impl<'a> From<&'a str> for Lexer<'a> {
     fn from(input: &'a str) -> Self {
-        Lexer {
-            input_len: input.len(),
-            chars: input.chars(),
-            mode: LexerMode::Outside(0),
-            buffer: None,
+        Lexer::with_mode(input, LexerMode::Outside(0))
+    }
+}
+
+/// Yields the remaining tokens, up to (but not including) the `Eof` token.
+/// Iterating by reference (`for token in &mut lexer`) leaves the lexer
+/// available for `take_errors` afterwards.
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        if self.peek().kind == TokenKind::Eof {
+            return None;
         }
+        Some(self.pop())
     }
 }
 
 fn is_whitespace(c: char) -> bool {
-    match c {
-        ' ' | '\t' | '\n' | '\r' => true,
-        _ => false,
+    matches!(c, ' ' | '\t' | '\n' | '\r')
+}
+
+/// The characters allowed in tag and attribute names: those that can start a
+/// name, and those that can follow the first. By default, names start with a
+/// letter or `:`, and continue with letters, digits, and any of `:._-`.
+///
+/// Characters that have a meaning of their own inside tags (whitespace, `<`,
+/// `>`, `/`, `=`, `#` and quotes) shouldn't be allowed in names, as they
+/// would no longer be recognized.
+#[derive(Clone, Copy, Debug)]
+pub struct NameChars {
+    pub start: fn(char) -> bool,
+    pub rest: fn(char) -> bool,
+}
+
+impl Default for NameChars {
+    fn default() -> Self {
+        NameChars {
+            start: is_name_start,
+            rest: is_name_continue,
+        }
     }
 }
 
+// `:` separates a name's prefix from its local name (as in `math:Equation`).
+// It's lexed anywhere in a name, so that the parser can report a misplaced
+// one rather than the lexer skipping it.
 fn is_name_start(c: char) -> bool {
-    match c {
-        'a'..='z' | 'A'..='Z' => true,
-        _ => false,
-    }
+    c.is_alphabetic() || c == ':'
 }
 
+// Tag and attribute names are both lexed as `Name` tokens, so the characters
+// allowed here apply to both. In particular, `.` (used to qualify tag names,
+// as in `CodeListing.Racket`) is a literal part of an attribute name: in
+// `data.id="x"`, the attribute is named `data.id`.
 fn is_name_continue(c: char) -> bool {
     match c {
-        c if is_name_start(c) => true,
-        '0'..='9' | '.' | '_' | '-' => true,
+        c if is_name_start(c) || c.is_alphanumeric() => true,
+        '.' | '_' | '-' => true,
         _ => false,
     }
 }
 
 #[derive(Debug)]
-pub struct Token {
+pub struct Token<'a> {
     pub kind: TokenKind,
     pub span: Span,
+    text: &'a str,
 }
 
-impl Token {
+impl<'a> Token<'a> {
     pub fn start(&self) -> usize {
         self.span.start
     }
@@ -247,21 +595,11 @@ impl Token {
     }
 
     pub fn text(&self) -> String {
-        FILE_INFO.with(|info| {
-            let info = info.borrow();
-            String::from(&info.text[self.span.start..self.span.end])
-        })
+        String::from(self.text)
     }
 
-    fn new(kind: TokenKind, start: usize, end: usize) -> Self {
-        Token {
-            kind,
-            span: Span::new(start, end),
-        }
-    }
-
-    fn eof(pos: usize) -> Self {
-        Token::new(TokenKind::Eof, pos, pos)
+    pub fn as_str(&self) -> &'a str {
+        self.text
     }
 }
 
@@ -270,17 +608,364 @@ pub enum TokenKind {
     LAngle,
     LAngleSlash,
     RAngle,
+    // "/>", ending a self-closing tag
+    SlashRAngle,
     Name,
     Equals,
     AttrVal,
     UnterminatedAttrVal,
     OrphanHashes,
     Text,
+    // Text inside a hash-fenced element
+    RawText,
+    // "<!--" through "-->", in an element's content
+    Comment,
     Eof,
 }
 
-#[derive(PartialEq)]
+/// The lexer's state. The count in each variant is the number of hashes
+/// fencing the content of the element being lexed (zero if it isn't fenced).
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum LexerMode {
+    /// Inside a tag, between '<' and '>'. Entering a close tag ("</") skips
+    /// the given number of hashes after the slash.
     Inside(usize),
+    /// Between tags, in an element's content. With a nonzero count the
+    /// content is raw, and only ends at "</" followed by that many hashes.
     Outside(usize),
 }
+
+// Describes a run of `count` hashes that isn't part of a fence, along with
+// the fences it could have been.
+pub(crate) fn orphaned_hashes_message(count: usize) -> String {
+    let hashes = "#".repeat(count);
+    format!(
+        r#"orphaned hashes (found {} '#' not part of a fence; expected "{}>" to end an open tag, or "</{}" to close an element fenced with them)"#,
+        count, hashes, hashes
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::syntax_error::Severity;
+
+    fn lex(input: &str) -> Vec<(TokenKind, String)> {
+        Lexer::from(input)
+            .map(|token| {
+                let text = token.text();
+                (token.kind, text)
+            })
+            .collect()
+    }
+
+    fn raw_text(input: &str) -> Vec<String> {
+        lex(input)
+            .into_iter()
+            .filter(|(kind, _)| *kind == TokenKind::RawText)
+            .map(|(_, text)| text)
+            .collect()
+    }
+
+    #[test]
+    fn hash_fence_without_nesting() {
+        assert_eq!(raw_text("<Code#>x < y</#Code>"), vec!["x < y"]);
+    }
+
+    #[test]
+    fn nested_hash_fence() {
+        assert_eq!(
+            raw_text("<Code#><Code#>x</#Code></#Code>"),
+            vec!["<Code#>x</#Code>"]
+        );
+    }
+
+    #[test]
+    fn doubly_nested_hash_fence() {
+        assert_eq!(
+            raw_text("<Code #><Code lang=\"hs\" #>a<Code#>b</#Code></# Code>c</# Code>"),
+            vec!["<Code lang=\"hs\" #>a<Code#>b</#Code></# Code>c"]
+        );
+
+        let tokens = lex("<Code#><Code#><Code#>x</#Code></#Code></#Code>");
+        let kinds: Vec<&TokenKind> = tokens.iter().map(|(kind, _)| kind).collect();
+        use TokenKind::*;
+        assert_eq!(
+            kinds,
+            vec![
+                &LAngle,
+                &Name,
+                &RAngle,
+                &RawText,
+                &LAngleSlash,
+                &Name,
+                &RAngle
+            ]
+        );
+        assert_eq!(tokens[3].1, "<Code#><Code#>x</#Code></#Code>");
+    }
+
+    #[test]
+    fn stronger_fences_contain_weaker_ones() {
+        assert_eq!(raw_text("<Code#>a</##Code>b</#Code>"), vec!["a</##Code>b"]);
+        assert_eq!(
+            raw_text("<Code##>a<Code#>b</#Code>c</##Code>"),
+            vec!["a<Code#>b</#Code>c"]
+        );
+        assert_eq!(
+            raw_text("<Code###>a<Code##>b<Code#>c</#Code></##Code>d</###Code>"),
+            vec!["a<Code##>b<Code#>c</#Code></##Code>d"]
+        );
+    }
+
+    #[test]
+    fn equal_fences_nest_inside_stronger_ones() {
+        assert_eq!(
+            raw_text("<Code##>a<Code##>b</##Code><Code#>c</##Code>"),
+            vec!["a<Code##>b</##Code><Code#>c"]
+        );
+        assert_eq!(
+            raw_text("<Code###><Code###>a</##Code></###Code>b</###Code>"),
+            vec!["<Code###>a</##Code></###Code>b"]
+        );
+    }
+
+    #[test]
+    fn other_tags_do_not_nest() {
+        assert_eq!(
+            raw_text("<Code#><Codex#>x</#Code>y</#Code>"),
+            vec!["<Codex#>x"]
+        );
+    }
+
+    #[test]
+    fn unknown_char_error_policy() {
+        let mut lexer = Lexer::from(r#"<Doc ; title="x">"#);
+        lexer.set_unknown_char_policy(UnknownCharPolicy::Error);
+
+        let mut kinds = vec![];
+        while lexer.peek().kind != TokenKind::Eof {
+            kinds.push(lexer.pop().kind);
+        }
+
+        use TokenKind::*;
+        assert_eq!(kinds, vec![LAngle, Name, Name, Equals, AttrVal, RAngle]);
+
+        let errors = lexer.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span, Span::new(5, 6));
+        assert_eq!(errors[0].message, "unexpected character ';'");
+    }
+
+    #[test]
+    fn tokens_keep_their_spans() {
+        let spans: Vec<(&str, Span)> = Lexer::from(r#"<Doc a="x">hi</Doc>"#)
+            .map(|token| (token.as_str(), token.span))
+            .collect();
+
+        assert_eq!(
+            spans,
+            vec![
+                ("<", Span::new(0, 1)),
+                ("Doc", Span::new(1, 4)),
+                ("a", Span::new(5, 6)),
+                ("=", Span::new(6, 7)),
+                ("x", Span::new(8, 9)),
+                (">", Span::new(10, 11)),
+                ("hi", Span::new(11, 13)),
+                ("</", Span::new(13, 15)),
+                ("Doc", Span::new(15, 18)),
+                (">", Span::new(18, 19)),
+            ]
+        );
+    }
+
+    #[test]
+    fn lexing_alone_reports_anomalies() {
+        let mut lexer = Lexer::from("<Doc a=\"x\n b ; c>\n</## Doc>");
+        for _ in &mut lexer {}
+
+        let errors: Vec<(Span, Severity)> = lexer
+            .take_errors()
+            .iter()
+            .map(|error| (error.span, error.severity))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (Span::new(8, 9), Severity::Error),
+                (Span::new(13, 14), Severity::Warning),
+                (Span::new(20, 22), Severity::Error),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_char_recover_policy() {
+        let mut lexer = Lexer::from(r#"<Doc ; title="x">"#);
+        lexer.set_unknown_char_policy(UnknownCharPolicy::Recover);
+
+        let mut kinds = vec![];
+        while lexer.peek().kind != TokenKind::Eof {
+            kinds.push(lexer.pop().kind);
+        }
+
+        use TokenKind::*;
+        assert_eq!(kinds, vec![LAngle, Name, Name, Equals, AttrVal, RAngle]);
+        assert_eq!(
+            lexer.take_errors(),
+            vec![SyntaxError::warning(
+                Span::new(5, 6),
+                "unexpected character ';' (skipped)"
+            )]
+        );
+    }
+
+    #[test]
+    fn resume_in_hash_fenced_content() {
+        let mut lexer = Lexer::with_mode("a <b> c</## Code>", LexerMode::Outside(2));
+
+        let mut tokens = vec![];
+        while lexer.peek().kind != TokenKind::Eof {
+            let token = lexer.pop();
+            let text = token.text();
+            tokens.push((token.kind, text));
+        }
+
+        use TokenKind::*;
+        assert_eq!(
+            tokens,
+            vec![
+                (RawText, String::from("a <b> c")),
+                (LAngleSlash, String::from("</##")),
+                (Name, String::from("Code")),
+                (RAngle, String::from(">")),
+            ]
+        );
+    }
+
+    #[test]
+    fn element_attr_values_return_to_the_enclosing_tag() {
+        let src = r#"<Cell content=<b>x</b> n="1">y</Cell>"#;
+        let mut lexer = Lexer::from(src);
+        lexer.set_element_attr_values(true);
+
+        let mut kinds = vec![];
+        loop {
+            let token = lexer.pop();
+            if token.kind == TokenKind::Eof {
+                break;
+            }
+            kinds.push(token.kind);
+        }
+
+        use TokenKind::*;
+        assert_eq!(
+            kinds,
+            vec![
+                LAngle,
+                Name,
+                Name,
+                Equals,
+                LAngle,
+                Name,
+                RAngle,
+                Text,
+                LAngleSlash,
+                Name,
+                RAngle,
+                Name,
+                Equals,
+                AttrVal,
+                RAngle,
+                Text,
+                LAngleSlash,
+                Name,
+                RAngle,
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_between_text() {
+        use TokenKind::*;
+        assert_eq!(
+            lex("<a>x<!-- <b> -->y</a>"),
+            vec![
+                (LAngle, String::from("<")),
+                (Name, String::from("a")),
+                (RAngle, String::from(">")),
+                (Text, String::from("x")),
+                (Comment, String::from("<!-- <b> -->")),
+                (Text, String::from("y")),
+                (LAngleSlash, String::from("</")),
+                (Name, String::from("a")),
+                (RAngle, String::from(">")),
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_sections_are_raw_text() {
+        use TokenKind::*;
+        assert_eq!(
+            lex("<a>x<![RAW[<b> & </a>]]>y</a>"),
+            vec![
+                (LAngle, String::from("<")),
+                (Name, String::from("a")),
+                (RAngle, String::from(">")),
+                (Text, String::from("x")),
+                (RawText, String::from("<b> & </a>")),
+                (Text, String::from("y")),
+                (LAngleSlash, String::from("</")),
+                (Name, String::from("a")),
+                (RAngle, String::from(">")),
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_raw_section_is_reported_at_its_start() {
+        let mut lexer = Lexer::from("<a>x<![RAW[<b>");
+        while lexer.pop().kind != TokenKind::Eof {}
+
+        let errors = lexer.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "unterminated raw section");
+        assert_eq!(errors[0].span, Span::new(4, 11));
+    }
+
+    #[test]
+    fn fail_fast_stops_at_unterminated_attr_value() {
+        let src = "<Doc title=\"x\n><Title>hi</Title></Doc>";
+        let mut lexer = Lexer::from(src);
+        lexer.set_fail_fast(true);
+
+        let kinds: Vec<TokenKind> = (0..6).map(|_| lexer.pop().kind).collect();
+
+        use TokenKind::*;
+        assert_eq!(kinds, vec![LAngle, Name, Name, Equals, Eof, Eof]);
+        let errors = lexer.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "unterminated attribute value");
+        assert_eq!(errors[0].span, Span::new(11, 13));
+    }
+
+    #[test]
+    fn single_quoted_attr_values() {
+        use TokenKind::*;
+        assert_eq!(
+            lex(r#"<Doc title='My "Doc"' b='it\'s'>"#)
+                .into_iter()
+                .filter(|(kind, _)| *kind == AttrVal)
+                .map(|(_, text)| text)
+                .collect::<Vec<_>>(),
+            vec![r#"My "Doc""#, r"it\'s"]
+        );
+
+        assert_eq!(
+            lex("<Doc title='x\n").last().unwrap(),
+            &(UnterminatedAttrVal, String::from("x"))
+        );
+    }
+}
@@ -1,32 +1,137 @@
 mod common;
+mod entity;
 mod file;
 mod lexer;
 mod parser;
 mod processor;
+mod render;
+mod schema;
+mod select;
+mod stream;
 mod syntax_error;
 mod tree;
 
-use common::FILE_INFO;
 use file::read_file;
+use std::io::{self, Read};
+use std::sync::Arc;
 
-pub use processor::{Processor, Transform};
-pub use tree::{Attrs, Tree};
+pub use common::{char_offsets, Span};
+pub use entity::EntityResolver;
+pub use file::SourceFile;
+pub use lexer::{Lexer, LexerMode, NameChars, Token, TokenKind, UnknownCharPolicy};
+pub use parser::{
+    parse_events, reparse_element, AttrLengthPolicy, EventHandler, ParseOptions, ParseResult,
+    Tree as SyntaxTree, TreeKind,
+};
+pub use processor::{
+    Data, MergeError, OnConflict, PrefixTransform, ProcessError, Processor, TraceEvent, Transform,
+};
+pub use render::Renderer;
+pub use schema::{Children, ComponentSchema, ValidationError, ValidationErrors};
+pub use select::SelectorError;
+pub use stream::{render_streaming, StreamError};
+pub use syntax_error::{ErrorKind, Severity, SyntaxError, SyntaxErrors};
+pub use tree::{
+    escape_attr, escape_text, parse_to_tree, AttrValue, Attrs, AttrsExt, DisplayEscaped, Tree,
+    POSITIONAL_ATTR,
+};
 
+/// Parses `input` into a tree. The tree is only returned if there are no
+/// errors (warnings are fine). The errors are displayed with `input` as
+/// their source.
+pub fn parse_str(input: &str) -> (Option<Tree>, Vec<SyntaxError>) {
+    parse_str_with(input, &ParseOptions::new())
+}
+
+pub fn parse_str_with(input: &str, options: &ParseOptions) -> (Option<Tree>, Vec<SyntaxError>) {
+    let result = parser::parse(input, options);
+    let errors = syntax_error::with_input_source(result.errors, input);
+
+    if errors.iter().any(SyntaxError::is_error) {
+        (None, errors)
+    } else {
+        (Some(Tree::from(result.tree)), errors)
+    }
+}
+
+/// Reads all of `r` and parses it like `parse_str`. The whole input is
+/// buffered before parsing starts (the lexer needs to look ahead), so this
+/// isn't suited to unbounded streams. Fails only if reading does, including
+/// if the input isn't valid UTF-8.
+pub fn parse_reader<R>(mut r: R) -> io::Result<(Option<Tree>, Vec<SyntaxError>)>
+where
+    R: Read,
+{
+    let mut input = String::new();
+    r.read_to_string(&mut input)?;
+    Ok(parse_str(&input))
+}
+
+/// Processes the document at `path` (or on standard input, if `path` is
+/// "-"), and prints the result. If the document
+/// has syntax errors, they're returned as `SyntaxErrors` (and any warnings
+/// are printed), and if it doesn't match the processor's schemas, the
+/// problems are returned as `ValidationErrors`.
 pub fn run(path: &str, proc: &Processor) -> Result<(), Box<dyn std::error::Error + 'static>> {
-    read_file(path)?;
+    run_with(path, proc, &ParseOptions::new())
+}
 
-    FILE_INFO.with(|info| {
-        let info = info.borrow();
-        let text = &info.text;
-        let result = parser::parse(text);
+pub fn run_with(
+    path: &str,
+    proc: &Processor,
+    options: &ParseOptions,
+) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    let source = Arc::new(read_file(path)?);
+    let tree = parse_source(&source, options)?;
+    let invalid = proc.validate(&tree);
+    if !invalid.is_empty() {
+        return Err(Box::new(ValidationErrors(
+            invalid
+                .into_iter()
+                .map(|error| error.with_source(Arc::clone(&source)))
+                .collect(),
+        )));
+    }
 
-        if result.errors.len() > 0 {
-            eprintln!("ERRORS");
-        } else {
-            let out = proc.process(Tree::from(result.tree));
-            println!("{}", out);
-        }
-    });
+    let out = proc
+        .process(tree)
+        .map_err(|error| error.with_source(Arc::clone(&source)))?;
+    println!("{}", out);
 
     Ok(())
 }
+
+/// Checks the document at `path` (or on standard input, if `path` is "-")
+/// for syntax errors, without processing it. Any warnings are printed, and
+/// the errors are returned as `SyntaxErrors`.
+pub fn run_check(path: &str) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    run_check_with(path, &ParseOptions::new())
+}
+
+pub fn run_check_with(
+    path: &str,
+    options: &ParseOptions,
+) -> Result<(), Box<dyn std::error::Error + 'static>> {
+    let source = Arc::new(read_file(path)?);
+    parse_source(&source, options)?;
+    Ok(())
+}
+
+// Parses `source`, printing any warnings, unless there are errors.
+fn parse_source(source: &Arc<SourceFile>, options: &ParseOptions) -> Result<Tree, SyntaxErrors> {
+    let result = parser::parse(source.text(), options);
+    let errors: Vec<_> = result
+        .errors
+        .into_iter()
+        .map(|error| error.with_source(Arc::clone(source)))
+        .collect();
+
+    if errors.iter().any(SyntaxError::is_error) {
+        return Err(SyntaxErrors(errors));
+    }
+    for warning in &errors {
+        eprintln!("{}", warning);
+    }
+
+    Ok(Tree::from(result.tree))
+}
@@ -1,17 +1,35 @@
-use hyli::{run, Attrs, Processor, Tree};
+use hyli::{run, run_check, Attrs, Processor, Tree};
+use std::env;
+use std::process;
+
+// Usage: hyli [--check] [PATH], where a PATH of "-" reads the document from
+// standard input. With --check, the document is only checked for syntax
+// errors.
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let check = args.first().map(String::as_str) == Some("--check");
+    if check {
+        args.remove(0);
+    }
+    let path = args
+        .into_iter()
+        .next()
+        .unwrap_or_else(|| String::from("./test.xml"));
 
-fn main() -> Result<(), Box<dyn std::error::Error + 'static>> {
     let mut proc = Processor::new();
     proc.add_transform("Doc", transform_doc);
 
-    run("./test.xml", &proc)?;
-    Ok(())
+    let result = if check {
+        run_check(&path)
+    } else {
+        run(&path, &proc)
+    };
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
 }
 
-fn transform_doc(attrs: Attrs, children: Vec<Tree>) -> Tree {
-    Tree::Inner {
-        tag_name: String::from("html"),
-        attrs: vec![],
-        children: vec![],
-    }
+fn transform_doc(_attrs: Attrs, _children: Vec<Tree>) -> Tree {
+    Tree::element("html", vec![], vec![])
 }
@@ -1,6 +1,9 @@
 use super::common::Span;
-use super::lexer::{Lexer, TokenKind as Tk};
-use super::syntax_error::SyntaxError;
+use super::entity::{decode_entities, EntityResolver};
+use super::lexer::{Lexer, LexerMode, NameChars, Token, TokenKind as Tk, UnknownCharPolicy};
+use super::syntax_error::{self, ErrorKind, SyntaxError};
+use super::tree::{AttrValue, Attrs, POSITIONAL_ATTR};
+use std::collections::HashSet;
 use std::fmt;
 
 #[derive(PartialEq)]
@@ -8,6 +11,9 @@ pub struct Tree {
     pub kind: TreeKind,
     pub span: Span,
     pub children: Vec<Tree>,
+    // Set on nodes the parser had to complete itself, e.g. by inserting a
+    // missing close tag.
+    pub recovered: bool,
 }
 
 impl fmt::Debug for Tree {
@@ -17,9 +23,21 @@ impl fmt::Debug for Tree {
 }
 
 impl Tree {
+    // Shifts every span in the tree `offset` bytes later.
+    fn rebase(&mut self, offset: usize) {
+        self.span = Span::new(self.span.start + offset, self.span.end + offset);
+        for child in &mut self.children {
+            child.rebase(offset);
+        }
+    }
+
     fn fmt_debug(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
         let indent = " ".repeat(depth * 2);
-        writeln!(f, "{}{:?}@{:?}", indent, self.kind, self.span)?;
+        write!(f, "{}{:?}@{:?}", indent, self.kind, self.span)?;
+        if self.recovered {
+            write!(f, " (recovered)")?;
+        }
+        writeln!(f)?;
 
         for child in &self.children {
             child.fmt_debug(f, depth + 1)?;
@@ -36,45 +54,250 @@ pub enum TreeKind {
     OpenTag,
     TagName(String),
     Attrs,
+    // An `AttrName`, followed by either an `AttrVal`, an `InnerNode` (for
+    // element-valued attributes), or nothing (for boolean attributes)
     Attr,
     AttrName(String),
     AttrVal(String),
     CloseTag,
     TextNode(String),
+    // The content of a hash-fenced element (like `<Code#>...</#Code>`), as
+    // written
+    RawTextNode(String),
+    // The text between "<!--" and "-->"
+    Comment(String),
 }
 
-pub fn parse<'a>(input: &'a str) -> ParseResult {
-    let mut tokens = Lexer::from(input);
-    let mut builder = TreeBuilder::new();
+#[derive(Clone, Default)]
+pub struct ParseOptions {
+    entity_resolver: Option<EntityResolver>,
+    unknown_char_policy: UnknownCharPolicy,
+    name_chars: NameChars,
+    max_attr_value_len: Option<(usize, AttrLengthPolicy)>,
+    element_attr_values: bool,
+    fail_fast: bool,
+    void_tags: HashSet<String>,
+    skip_comments: bool,
+    max_depth: Option<usize>,
+}
+
+// The deepest elements can be nested unless `ParseOptions::set_max_depth`
+// says otherwise
+const DEFAULT_MAX_DEPTH: usize = 256;
+
+/// What to do with attribute values longer than the maximum length.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AttrLengthPolicy {
+    Error,
+    /// Keep the start of the value, and warn that it was truncated.
+    Truncate,
+}
+
+impl ParseOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a resolver for named entities other than the predefined ones
+    /// (`&amp;`, `&lt;`, `&gt;`, `&quot;` and `&apos;`).
+    pub fn set_entity_resolver(&mut self, resolver: EntityResolver) {
+        self.entity_resolver = Some(resolver);
+    }
+
+    pub fn set_unknown_char_policy(&mut self, policy: UnknownCharPolicy) {
+        self.unknown_char_policy = policy;
+    }
+
+    /// Sets the characters allowed in tag and attribute names (see
+    /// `NameChars`).
+    pub fn set_name_chars(&mut self, name_chars: NameChars) {
+        self.name_chars = name_chars;
+    }
+
+    /// Limits attribute values to `max_len` bytes of source text. Longer
+    /// values are cut down to the limit in either case, so they never take
+    /// up more memory than that.
+    pub fn set_max_attr_value_len(&mut self, max_len: usize, policy: AttrLengthPolicy) {
+        self.max_attr_value_len = Some((max_len, policy));
+    }
+
+    /// Stops parsing at the first lexical error (see `Lexer::set_fail_fast`).
+    /// The rest of the document is parsed as if the input ended there, so
+    /// unfinished elements are reported as truncated as well.
+    pub fn set_fail_fast(&mut self, fail_fast: bool) {
+        self.fail_fast = fail_fast;
+    }
+
+    /// Allows an attribute's value to be an element instead of a quoted
+    /// string, as in `<Cell content=<b>x</b>>`. The value extends from the
+    /// '<' following '=' to the matching close tag, and may itself contain
+    /// any content (including further element-valued attributes); the
+    /// enclosing tag then continues with its next attribute or its '>'.
+    pub fn set_element_attr_values(&mut self, enabled: bool) {
+        self.element_attr_values = enabled;
+    }
+
+    /// Sets the tags of void elements, which end with their open tag (as if
+    /// it were self-closing), so that `<Hr>` needs no `</Hr>`. A close tag
+    /// for a void element is reported, and skipped.
+    pub fn set_void_tags<I, S>(&mut self, names: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.void_tags = names.into_iter().map(|name| name.into()).collect();
+    }
+
+    /// Leaves comments out of the tree. They're kept (as `Comment` nodes) by
+    /// default.
+    pub fn set_skip_comments(&mut self, skip: bool) {
+        self.skip_comments = skip;
+    }
 
-    parse_document(&mut builder, &mut tokens);
+    /// Limits how deeply elements can be nested (the root being at depth
+    /// 1), so that untrusted input can't exhaust the stack. An element
+    /// beyond the limit is reported, and skipped along with its content.
+    /// The limit is 256 by default.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = Some(max_depth);
+    }
+
+    fn max_depth(&self) -> usize {
+        self.max_depth.unwrap_or(DEFAULT_MAX_DEPTH)
+    }
+}
 
+pub fn parse(input: &str, options: &ParseOptions) -> ParseResult {
+    let mut builder = TreeBuilder::new(options.clone());
+    parse_into(input, &mut builder);
     builder.take()
 }
 
-fn parse_document<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) {
+// Parses `input`, reporting the structure of the document to `sink`.
+pub fn parse_into(input: &str, sink: &mut dyn TreeSink) {
+    let mut tokens = Lexer::from(input);
+    tokens.set_unknown_char_policy(sink.options().unknown_char_policy);
+    tokens.set_name_chars(sink.options().name_chars);
+    tokens.set_element_attr_values(sink.options().element_attr_values);
+    tokens.set_fail_fast(sink.options().fail_fast);
+    parse_document(sink, &mut tokens);
+
+    for error in tokens.take_errors() {
+        sink.add_error(error);
+    }
+}
+
+/// Parses just the element covering `span` in `source`, as when the element
+/// has been edited and the rest of the document is unchanged. The spans in
+/// the result (including those of errors) are offsets into `source`, not
+/// into the element's text. If `span` isn't a range of whole characters in
+/// `source`, that's reported as an error at the end of `source`.
+pub fn reparse_element(source: &str, span: Span, options: &ParseOptions) -> ParseResult {
+    let mut builder = TreeBuilder::new(options.clone());
+
+    let in_range = span.start <= span.end && span.end <= source.len();
+    if !in_range || !source.is_char_boundary(span.start) || !source.is_char_boundary(span.end) {
+        let end = source.len();
+        builder.open(TreeKind::InnerNode, end);
+        builder.add_error(SyntaxError::new(
+            Span::new(end, end),
+            format!(
+                "span {}..{} isn't a range of whole characters in the source",
+                span.start, span.end
+            ),
+        ));
+        builder.complete_recovered(end);
+
+        let mut result = builder.take();
+        result.errors = syntax_error::with_input_source(result.errors, source);
+        return result;
+    }
+
+    let mut tokens = Lexer::with_mode(&source[span.start..span.end], LexerMode::Outside(0));
+    tokens.set_unknown_char_policy(options.unknown_char_policy);
+    tokens.set_name_chars(options.name_chars);
+    tokens.set_element_attr_values(options.element_attr_values);
+    tokens.set_fail_fast(options.fail_fast);
+
+    let peek = tokens.peek();
+    if peek.kind == Tk::LAngle {
+        parse_inner_node(&mut builder, &mut tokens, &mut vec![]);
+    } else {
+        // There's no element, but the result still needs a tree
+        builder.open(TreeKind::InnerNode, peek.start());
+        builder.add_error(unexpected(peek, "expected '<'"));
+        builder.complete_recovered(peek.start());
+    }
+
+    let peek = tokens.peek();
+    if peek.kind != Tk::Eof {
+        let end = span.end - span.start;
+        builder.add_error(SyntaxError::new(
+            Span::new(peek.start(), end),
+            "expected the element to end here",
+        ));
+    }
+
+    for error in tokens.take_errors() {
+        builder.add_error(error);
+    }
+
+    let mut result = builder.take();
+    result.tree.rebase(span.start);
+    for error in &mut result.errors {
+        error.span = Span::new(error.span.start + span.start, error.span.end + span.start);
+    }
+    result.errors = syntax_error::with_input_source(result.errors, source);
+    result
+}
+
+fn parse_document<'a>(builder: &mut dyn TreeSink, tokens: &mut Lexer<'a>) {
     builder.open(TreeKind::Document, tokens.peek().start());
     loop {
         let peek = tokens.peek();
         match peek.kind {
             Tk::LAngle => break,
             Tk::Eof => {
-                builder.add_error(SyntaxError::new(peek.span, "unexpected EOF"));
+                builder.add_error(unexpected(peek, "unexpected EOF"));
                 builder.complete(peek.start());
                 return;
             }
+            Tk::Text => {
+                // Text before the root isn't part of the document, which is
+                // most likely a mistake unless it's only whitespace
+                let text = peek.as_str();
+                let trimmed = text.trim_start();
+                if !trimmed.is_empty() {
+                    let start = peek.start() + text.len() - trimmed.len();
+                    builder.add_error(SyntaxError::warning(
+                        Span::new(start, start + trimmed.trim_end().len()),
+                        "text before the root element is ignored",
+                    ));
+                }
+                tokens.pop();
+            }
             _ => {
                 tokens.pop();
             }
         }
     }
 
-    parse_inner_node(builder, tokens);
+    parse_inner_node(builder, tokens, &mut vec![]);
 
     loop {
         let peek = tokens.peek();
         match peek.kind {
             Tk::Eof => break,
+            Tk::LAngle => {
+                // Any other roots are parsed (so that the syntax tree covers
+                // them), but only the first is the document
+                let start = peek.start();
+                parse_inner_node(builder, tokens, &mut vec![]);
+                builder.add_error(SyntaxError::new(
+                    Span::new(start, tokens.peek().start()),
+                    "documents must have a single root element",
+                ));
+            }
             _ => {
                 tokens.pop();
             }
@@ -84,13 +307,32 @@ fn parse_document<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) {
     builder.complete(tokens.peek().start());
 }
 
-fn parse_nodes<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) {
+// `ancestors` are the names of the elements enclosing the nodes.
+fn parse_nodes<'a>(
+    builder: &mut dyn TreeSink,
+    tokens: &mut Lexer<'a>,
+    ancestors: &mut Vec<String>,
+) {
     loop {
         let peek = tokens.peek();
         match peek.kind {
-            Tk::LAngleSlash | Tk::Eof => return,
-            Tk::LAngle => parse_inner_node(builder, tokens),
-            Tk::Text => parse_text_node(builder, tokens),
+            Tk::LAngleSlash => match tokens.peek_close_tag_name() {
+                Some((name, span)) if builder.options().void_tags.contains(name) => {
+                    builder.add_error(SyntaxError::new(
+                        span,
+                        format!(
+                            r#"void element "{}" can't have content or a closing tag"#,
+                            name
+                        ),
+                    ));
+                    skip_close_tag(tokens);
+                }
+                _ => return,
+            },
+            Tk::Eof => return,
+            Tk::LAngle => parse_nested_node(builder, tokens, ancestors),
+            Tk::Text | Tk::RawText => parse_text_node(builder, tokens),
+            Tk::Comment => parse_comment(builder, tokens),
             _ => {
                 builder.add_error(SyntaxError::new(
                     peek.span,
@@ -105,44 +347,174 @@ fn parse_nodes<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) {
     }
 }
 
-fn parse_inner_node<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) {
-    builder.open(TreeKind::InnerNode, tokens.peek().start());
-    let open_tag_name = parse_open_tag(builder, tokens);
-    parse_nodes(builder, tokens);
+// Parses the element starting at the next token, unless it would be nested
+// more deeply than the options allow, in which case it's skipped.
+fn parse_nested_node<'a>(
+    builder: &mut dyn TreeSink,
+    tokens: &mut Lexer<'a>,
+    ancestors: &mut Vec<String>,
+) {
+    if ancestors.len() < builder.options().max_depth() {
+        parse_inner_node(builder, tokens, ancestors);
+        return;
+    }
+
+    let start = tokens.peek().start();
+    skip_element(builder, tokens);
+    builder.add_error(SyntaxError::new(
+        Span::new(start, tokens.peek().start()),
+        format!(
+            "maximum nesting depth {} exceeded",
+            builder.options().max_depth()
+        ),
+    ));
+}
+
+fn parse_inner_node<'a>(
+    builder: &mut dyn TreeSink,
+    tokens: &mut Lexer<'a>,
+    ancestors: &mut Vec<String>,
+) {
+    let start = tokens.peek().start();
+    builder.open(TreeKind::InnerNode, start);
+    let (open_tag_name, self_closing) = parse_open_tag(builder, tokens, ancestors);
+    let open_tag_span = Span::new(start, tokens.peek().start());
+    let void = matches!(&open_tag_name, Some(name) if builder.options().void_tags.contains(name));
+    if self_closing || void {
+        // The element is just its open tag
+        builder.complete(tokens.peek().start());
+        return;
+    }
+    ancestors.push(open_tag_name.clone().unwrap_or_default());
+    parse_nodes(builder, tokens, ancestors);
+    ancestors.pop();
 
     let peek = tokens.peek();
     if peek.kind == Tk::Eof {
-        builder.complete(peek.start());
-        builder.add_error(SyntaxError::new(
-            peek.span,
-            "expected closing tag, but found EOF",
+        // Insert an empty close tag so that the node is still well-formed.
+        builder.add_leaf(TreeKind::CloseTag, peek.span);
+        builder.complete_recovered(peek.start());
+        // The open tag is more useful than the end of the input for finding
+        // the element that isn't closed
+        builder.add_error(SyntaxError::unexpected_eof(
+            open_tag_span,
+            format!(
+                r#"unclosed "<{}>" opened here (expected a closing tag, but found EOF)"#,
+                open_tag_name.as_deref().unwrap_or_default()
+            ),
         ));
         return;
     }
 
+    // A close tag for an enclosing element closes this one too, leaving the
+    // close tag to that element, so that the elements after it are still
+    // its siblings rather than this element's children.
+    if let Some((name, span)) = tokens.peek_close_tag_name() {
+        if open_tag_name.as_deref() != Some(name) && ancestors.iter().any(|a| a == name) {
+            let start = tokens.peek().start();
+            builder.add_leaf(TreeKind::CloseTag, Span::new(start, start));
+            builder.complete_recovered(start);
+            builder.add_error(mismatched_close_tag(
+                &open_tag_name.unwrap_or_default(),
+                name,
+                span,
+            ));
+            return;
+        }
+    }
+
     let close_tag = parse_close_tag(builder, tokens);
     builder.complete(tokens.peek().start());
 
     match (open_tag_name, close_tag) {
         (Some(open), Some(CloseTag { name, span })) if open != name => {
-            builder.add_error(SyntaxError::new(
-                span,
-                format!(
-                    r#"closing tag must match opening (expected "{}" but found "{}")"#,
-                    open, name
-                ),
-            ))
+            builder.add_error(mismatched_close_tag(&open, &name, span))
         }
         _ => {}
     }
 }
 
-fn parse_text_node(builder: &mut TreeBuilder, tokens: &mut Lexer) {
+// Skips the element starting at the next token, including its content,
+// without descending into it.
+fn skip_element(builder: &dyn TreeSink, tokens: &mut Lexer) {
+    let mut depth = 0;
+    // Whether the tag being skipped is a void element's open tag
+    let mut in_void_tag = false;
+    loop {
+        let token = tokens.pop();
+        match token.kind {
+            Tk::LAngle => {
+                depth += 1;
+                let name = tokens.peek();
+                in_void_tag =
+                    name.kind == Tk::Name && builder.options().void_tags.contains(name.as_str());
+            }
+            Tk::RAngle if in_void_tag => {
+                depth -= 1;
+                in_void_tag = false;
+            }
+            Tk::SlashRAngle => {
+                depth -= 1;
+                in_void_tag = false;
+            }
+            Tk::LAngleSlash => {
+                depth -= 1;
+                skip_close_tag(tokens);
+            }
+            Tk::Eof => return,
+            _ => {}
+        }
+        if depth == 0 {
+            return;
+        }
+    }
+}
+
+fn skip_close_tag(tokens: &mut Lexer) {
+    loop {
+        match tokens.pop().kind {
+            Tk::RAngle | Tk::Eof => return,
+            _ => {}
+        }
+    }
+}
+
+fn mismatched_close_tag(open: &str, close: &str, span: Span) -> SyntaxError {
+    SyntaxError::new(
+        span,
+        format!(
+            r#"closing tag must match opening (expected "{}" but found "{}")"#,
+            open, close
+        ),
+    )
+}
+
+fn parse_comment(builder: &mut dyn TreeSink, tokens: &mut Lexer) {
+    let comment = tokens.pop();
+    if builder.options().skip_comments {
+        return;
+    }
+    let text = comment.as_str()[4..].trim_end_matches("-->");
+    builder.add_leaf(TreeKind::Comment(String::from(text)), comment.span);
+}
+
+fn parse_text_node(builder: &mut dyn TreeSink, tokens: &mut Lexer) {
     let text = tokens.pop();
-    builder.add_leaf(TreeKind::TextNode(text.text()), text.span);
+    let kind = if text.kind == Tk::RawText {
+        TreeKind::RawTextNode(text.text())
+    } else {
+        TreeKind::TextNode(builder.decode_entities(&text.text(), text.start()))
+    };
+    builder.add_leaf(kind, text.span);
 }
 
-fn parse_open_tag<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) -> Option<String> {
+// Returns the tag's name, and whether it's self-closing (like `<br/>`).
+// `ancestors` are the names of the elements enclosing the tag's element.
+fn parse_open_tag<'a>(
+    builder: &mut dyn TreeSink,
+    tokens: &mut Lexer<'a>,
+    ancestors: &mut Vec<String>,
+) -> (Option<String>, bool) {
     let mut tag_name = None;
     let langle = tokens.pop();
     builder.open(TreeKind::OpenTag, langle.start());
@@ -151,6 +523,7 @@ fn parse_open_tag<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) -> Opti
     match peek.kind {
         Tk::Name => {
             let name = tokens.pop();
+            check_name(builder, &name);
             tag_name = Some(name.text().clone());
             builder.add_leaf(TreeKind::TagName(name.text()), name.span);
         }
@@ -158,48 +531,71 @@ fn parse_open_tag<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) -> Opti
             builder.add_error(SyntaxError::new(peek.span, "expected tag name"));
         }
         _ => {
-            builder.add_error(SyntaxError::new(
-                peek.span,
+            builder.add_error(unexpected(
+                peek,
                 "expected tag name, followed by attributes and '>'",
             ));
             builder.complete(peek.start());
-            return tag_name;
+            return (tag_name, false);
         }
     }
 
-    parse_attrs(builder, tokens);
+    // Element-valued attributes are nested in this element
+    ancestors.push(tag_name.clone().unwrap_or_default());
+    parse_attrs(builder, tokens, ancestors);
+    ancestors.pop();
 
     let peek = tokens.peek();
     let end = peek.end();
+    let self_closing = peek.kind == Tk::SlashRAngle;
     match peek.kind {
-        Tk::RAngle => {
+        Tk::RAngle | Tk::SlashRAngle => {
             tokens.pop();
         }
+        Tk::OrphanHashes => {
+            // The hashes are reported by the lexer
+            builder.complete(peek.start());
+            return (tag_name, false);
+        }
         _ => {
-            builder.add_error(SyntaxError::new(peek.span, "expected '>'"));
+            builder.add_error(unexpected(peek, "expected '>'"));
             builder.complete(peek.start());
-            return tag_name;
+            return (tag_name, false);
         }
     }
 
     builder.complete(end);
-    tag_name
+    (tag_name, self_closing)
+}
+
+// Checks that a prefixed name (like `math:Equation` or `xmlns:svg`) has both a
+// prefix and a local name.
+fn check_name(builder: &mut dyn TreeSink, name: &Token) {
+    let message = if name.as_str().starts_with(':') {
+        "expected a prefix before ':'"
+    } else if name.as_str().ends_with(':') {
+        "expected a name after ':'"
+    } else {
+        return;
+    };
+    builder.add_error(SyntaxError::new(name.span, message));
 }
 
-fn parse_close_tag<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) -> Option<CloseTag> {
+fn parse_close_tag<'a>(builder: &mut dyn TreeSink, tokens: &mut Lexer<'a>) -> Option<CloseTag> {
     let mut tag_info = None;
     let langle_slash = tokens.pop();
     builder.open(TreeKind::CloseTag, langle_slash.start());
 
+    // Orphaned hashes are reported by the lexer
     if tokens.peek().kind == Tk::OrphanHashes {
-        let orphans = tokens.pop();
-        builder.add_error(SyntaxError::new(orphans.span, "orphaned hashes"));
+        tokens.pop();
     }
 
     let peek = tokens.peek();
     match peek.kind {
         Tk::Name => {
             let name = tokens.pop();
+            check_name(builder, &name);
             tag_info = Some(CloseTag {
                 name: name.text().clone(),
                 span: name.span,
@@ -210,10 +606,7 @@ fn parse_close_tag<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) -> Opt
             builder.add_error(SyntaxError::new(peek.span, "expected tag name"));
         }
         _ => {
-            builder.add_error(SyntaxError::new(
-                peek.span,
-                "expected tag name, followed by '>'",
-            ));
+            builder.add_error(unexpected(peek, "expected tag name, followed by '>'"));
             builder.complete(peek.start());
             return tag_info;
         }
@@ -226,7 +619,7 @@ fn parse_close_tag<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) -> Opt
             tokens.pop();
         }
         _ => {
-            builder.add_error(SyntaxError::new(peek.span, "expected '>'"));
+            builder.add_error(unexpected(peek, "expected '>'"));
             builder.complete(peek.start());
             return tag_info;
         }
@@ -236,19 +629,83 @@ fn parse_close_tag<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) -> Opt
     tag_info
 }
 
-fn parse_attrs<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) {
+fn parse_attrs<'a>(
+    builder: &mut dyn TreeSink,
+    tokens: &mut Lexer<'a>,
+    ancestors: &mut Vec<String>,
+) {
     builder.open(TreeKind::Attrs, tokens.peek().start());
 
-    while tokens.peek().kind == Tk::Name {
-        parse_attr(builder, tokens);
+    // Repeated attributes are kept in the syntax tree, but only the first is
+    // kept when converting to a `tree::Tree`.
+    let mut names = vec![];
+    loop {
+        let peek = tokens.peek();
+        let name = match peek.kind {
+            Tk::Name => peek.as_str(),
+            Tk::AttrVal | Tk::UnterminatedAttrVal => POSITIONAL_ATTR,
+            _ => break,
+        };
+        if names.contains(&name) {
+            let message = if name == POSITIONAL_ATTR {
+                String::from("only one attribute can be positional")
+            } else {
+                format!(r#"duplicate attribute "{}""#, name)
+            };
+            builder.add_error(SyntaxError::new(peek.span, message));
+        } else {
+            names.push(name);
+        }
+
+        if peek.kind == Tk::Name {
+            parse_attr(builder, tokens, ancestors);
+        } else {
+            parse_positional_attr(builder, tokens);
+        }
     }
 
     builder.complete(tokens.peek().start());
 }
 
-fn parse_attr<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) {
+// Parses a value without a name (like `"photo.jpg"` in `<Img "photo.jpg">`),
+// which is given the name `POSITIONAL_ATTR`. The name's leaf is empty, at
+// the start of the value.
+fn parse_positional_attr(builder: &mut dyn TreeSink, tokens: &mut Lexer) {
+    let attr_val = tokens.pop();
+    // The quotes are excluded from the token
+    let start = attr_val.start() - 1;
+    builder.open(TreeKind::Attr, start);
+    builder.add_leaf(
+        TreeKind::AttrName(String::from(POSITIONAL_ATTR)),
+        Span::new(start, start),
+    );
+
+    // An unterminated value is reported by the lexer
+    let raw = limit_attr_value(builder, attr_val.as_str(), Span::new(start, attr_val.end()));
+    let value = decode_attr_value(builder, raw, attr_val.start());
+    builder.add_leaf(TreeKind::AttrVal(value), attr_val.span);
+
+    builder.complete(attr_val.end());
+}
+
+// `ancestors` are the names of the elements enclosing an element-valued
+// attribute's value, including the element whose attribute it is.
+fn parse_attr<'a>(builder: &mut dyn TreeSink, tokens: &mut Lexer<'a>, ancestors: &mut Vec<String>) {
     let name = tokens.pop();
     builder.open(TreeKind::Attr, name.start());
+    // Names can be namespaced (`xmlns:foo`) or dotted (`data.id`). Which
+    // characters they may contain is up to `ParseOptions::set_name_chars`,
+    // but `_` is always taken by positional attributes.
+    check_name(builder, &name);
+    if name.as_str() == POSITIONAL_ATTR {
+        builder.add_error(SyntaxError::new(
+            name.span,
+            format!(
+                r#"attribute name "{}" is reserved for positional attributes"#,
+                POSITIONAL_ATTR
+            ),
+        ));
+    }
     builder.add_leaf(TreeKind::AttrName(name.text()), name.span);
 
     let peek = tokens.peek();
@@ -260,11 +717,8 @@ fn parse_attr<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) {
             builder.add_error(SyntaxError::new(peek.span, "expected '='"));
         }
         _ => {
-            builder.add_error(SyntaxError::new(
-                peek.span,
-                "expected '=', followed by attribute value",
-            ));
-            builder.complete(peek.start());
+            // A boolean attribute, like `disabled` in `<Input disabled>`
+            builder.complete(name.end());
             return;
         }
     }
@@ -273,15 +727,19 @@ fn parse_attr<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) {
     let end = peek.end();
     match peek.kind {
         Tk::AttrVal | Tk::UnterminatedAttrVal => {
-            if peek.kind == Tk::UnterminatedAttrVal {
-                builder.add_error(SyntaxError::new(peek.span, "unterminated attribute value"));
-            }
-
+            // An unterminated value is reported by the lexer
             let attr_val = tokens.pop();
-            builder.add_leaf(TreeKind::AttrVal(attr_val.text()), attr_val.span);
+            let raw = limit_attr_value(builder, attr_val.as_str(), Span::new(name.start(), end));
+            let value = decode_attr_value(builder, raw, attr_val.start());
+            builder.add_leaf(TreeKind::AttrVal(value), attr_val.span);
+        }
+        Tk::LAngle if builder.options().element_attr_values => {
+            parse_nested_node(builder, tokens, ancestors);
+            builder.complete(tokens.peek().start());
+            return;
         }
         _ => {
-            builder.add_error(SyntaxError::new(peek.span, "expected attribute value"));
+            builder.add_error(unexpected(peek, "expected attribute value"));
             builder.complete(peek.start());
             return;
         }
@@ -290,27 +748,146 @@ fn parse_attr<'a>(builder: &mut TreeBuilder, tokens: &mut Lexer<'a>) {
     builder.complete(end);
 }
 
+// Decodes the backslash escapes (`\"`, `\'`, `\\`, `\n` and `\t`) and entities in
+// an attribute value. Unknown escapes are left as they are.
+fn decode_attr_value(builder: &mut dyn TreeSink, raw: &str, start: usize) -> String {
+    let mut value = String::with_capacity(raw.len());
+    let mut rest = raw;
+
+    while let Some(backslash) = rest.find('\\') {
+        let offset = raw.len() - rest.len();
+        value.push_str(&builder.decode_entities(&rest[..backslash], start + offset));
+
+        let escaped = match rest[backslash + 1..].chars().next() {
+            Some(c) => c,
+            None => {
+                value.push('\\');
+                rest = "";
+                break;
+            }
+        };
+        match escaped {
+            '"' => value.push('"'),
+            '\'' => value.push('\''),
+            '\\' => value.push('\\'),
+            'n' => value.push('\n'),
+            't' => value.push('\t'),
+            c => {
+                let escape_start = start + offset + backslash;
+                builder.add_error(SyntaxError::warning(
+                    Span::new(escape_start, escape_start + 1 + c.len_utf8()),
+                    format!("unknown escape sequence \"\\{}\"", c),
+                ));
+                value.push('\\');
+                value.push(c);
+            }
+        }
+        rest = &rest[backslash + 1 + escaped.len_utf8()..];
+    }
+
+    let offset = raw.len() - rest.len();
+    value.push_str(&builder.decode_entities(rest, start + offset));
+    value
+}
+
+fn limit_attr_value<'a>(builder: &mut dyn TreeSink, raw: &'a str, attr_span: Span) -> &'a str {
+    let (max_len, policy) = match builder.options().max_attr_value_len {
+        Some(limit) if raw.len() > limit.0 => limit,
+        _ => return raw,
+    };
+
+    let message = format!("attribute value is longer than {} bytes", max_len);
+    builder.add_error(match policy {
+        AttrLengthPolicy::Error => SyntaxError::new(attr_span, message),
+        AttrLengthPolicy::Truncate => {
+            SyntaxError::warning(attr_span, format!("{} (truncated)", message))
+        }
+    });
+
+    let mut end = max_len;
+    while !raw.is_char_boundary(end) {
+        end -= 1;
+    }
+    &raw[..end]
+}
+
+// An error for finding `token` where something else was expected. Finding
+// the end of the input means that the input is truncated.
+fn unexpected<S>(token: &Token, message: S) -> SyntaxError
+where
+    S: Into<String>,
+{
+    if token.kind == Tk::Eof {
+        SyntaxError::unexpected_eof(token.span, message)
+    } else {
+        SyntaxError::new(token.span, message)
+    }
+}
+
 struct CloseTag {
     name: String,
     span: Span,
 }
 
+/// A parsed document, along with any errors (in source order).
 #[derive(Debug)]
 pub struct ParseResult {
     pub tree: Tree,
     pub errors: Vec<SyntaxError>,
 }
 
+impl ParseResult {
+    /// Whether the input ended before the document did (inside a tag, or
+    /// with elements still open), so that more input might complete it.
+    pub fn is_truncated(&self) -> bool {
+        self.errors
+            .iter()
+            .any(|error| error.kind == ErrorKind::UnexpectedEof)
+    }
+}
+
+// Receives the structure of a document as it's parsed. Nodes are opened,
+// given children (as nested nodes or leaves), and then completed.
+pub trait TreeSink {
+    fn open(&mut self, kind: TreeKind, start: usize);
+
+    fn add_leaf(&mut self, kind: TreeKind, span: Span);
+
+    fn complete_with(&mut self, end: usize, recovered: bool);
+
+    fn add_error(&mut self, error: SyntaxError);
+
+    fn options(&self) -> &ParseOptions;
+
+    fn complete(&mut self, end: usize) {
+        self.complete_with(end, false);
+    }
+
+    fn complete_recovered(&mut self, end: usize) {
+        self.complete_with(end, true);
+    }
+
+    fn decode_entities(&mut self, text: &str, start: usize) -> String {
+        let (decoded, errors) = decode_entities(text, start, self.options().entity_resolver);
+        for error in errors {
+            self.add_error(error);
+        }
+        decoded
+    }
+}
+
 struct TreeBuilder {
     wip: Vec<BuilderItem>,
     errors: Vec<SyntaxError>,
+    options: ParseOptions,
 }
 
 impl TreeBuilder {
-    fn new() -> Self {
+    fn new(options: ParseOptions) -> Self {
         TreeBuilder {
             wip: vec![],
             errors: vec![],
+            options,
         }
     }
 
@@ -323,16 +900,22 @@ impl TreeBuilder {
             _ => panic!(""),
         };
 
-        if self.wip.len() > 0 {
+        if !self.wip.is_empty() {
             panic!("multiple trees in WIP");
         }
 
+        // Errors are found in several passes (lexing, and the parser's
+        // recovery), so they aren't necessarily in source order
+        self.errors.sort_by_key(|error| error.span);
+
         ParseResult {
             tree,
             errors: self.errors,
         }
     }
+}
 
+impl TreeSink for TreeBuilder {
     fn open(&mut self, kind: TreeKind, start: usize) {
         self.wip.push(BuilderItem::InProgress { kind, start });
     }
@@ -342,10 +925,11 @@ impl TreeBuilder {
             kind,
             span,
             children: vec![],
+            recovered: false,
         }));
     }
 
-    fn complete(&mut self, end: usize) {
+    fn complete_with(&mut self, end: usize, recovered: bool) {
         let mut children = vec![];
 
         while let Some(item) = self.wip.pop() {
@@ -356,6 +940,7 @@ impl TreeBuilder {
                         kind,
                         span: Span::new(start, end),
                         children,
+                        recovered,
                     }));
                     return;
                 }
@@ -371,9 +956,942 @@ impl TreeBuilder {
     fn add_error(&mut self, error: SyntaxError) {
         self.errors.push(error);
     }
+
+    fn options(&self) -> &ParseOptions {
+        &self.options
+    }
 }
 
 enum BuilderItem {
     InProgress { kind: TreeKind, start: usize },
     Complete(Tree),
 }
+
+/// Receives the elements and text of a document parsed by `parse_events`, in
+/// document order.
+pub trait EventHandler {
+    /// Called once an element's open tag is complete. `span` covers the open
+    /// tag.
+    fn open_tag(&mut self, name: &str, attrs: &Attrs, span: Span);
+
+    /// Called once an element (and all of its content) is complete. `span`
+    /// covers the close tag, and is empty (at the end of the element) for
+    /// elements without one, like `<br/>`.
+    fn close_tag(&mut self, name: &str, span: Span);
+
+    /// Called for each text node, including the content of hash-fenced
+    /// elements.
+    fn text(&mut self, text: &str, span: Span);
+
+    /// Called for each syntax error (and warning), as it's found. Errors
+    /// aren't reported in order of their spans.
+    fn error(&mut self, error: SyntaxError);
+}
+
+/// Parses `input`, reporting its elements and text to `handler` as they're
+/// found instead of building a tree. Only the tags currently open are kept,
+/// so memory use doesn't grow with the size of the document. Comments aren't
+/// reported.
+pub fn parse_events<H>(input: &str, options: &ParseOptions, handler: &mut H)
+where
+    H: EventHandler,
+{
+    let mut sink = EventSink {
+        handler,
+        options: options.clone(),
+        frames: vec![],
+    };
+    parse_into(input, &mut sink);
+}
+
+struct EventSink<'a, H> {
+    handler: &'a mut H,
+    options: ParseOptions,
+    // The nodes currently open, innermost last
+    frames: Vec<EventFrame>,
+}
+
+enum EventFrame {
+    // The tag name is recorded once the element's open tag is complete
+    InnerNode {
+        name: Option<String>,
+        close_span: Option<Span>,
+    },
+    OpenTag {
+        start: usize,
+        name: Option<String>,
+        attrs: Attrs,
+    },
+    Attr(Option<String>, Option<String>),
+    CloseTag(usize),
+    Other,
+}
+
+impl<'a, H> TreeSink for EventSink<'a, H>
+where
+    H: EventHandler,
+{
+    fn open(&mut self, kind: TreeKind, start: usize) {
+        let frame = match kind {
+            TreeKind::InnerNode => EventFrame::InnerNode {
+                name: None,
+                close_span: None,
+            },
+            TreeKind::OpenTag => EventFrame::OpenTag {
+                start,
+                name: None,
+                attrs: vec![],
+            },
+            TreeKind::Attr => EventFrame::Attr(None, None),
+            TreeKind::CloseTag => EventFrame::CloseTag(start),
+            _ => EventFrame::Other,
+        };
+        self.frames.push(frame);
+    }
+
+    fn add_leaf(&mut self, kind: TreeKind, span: Span) {
+        match (self.frames.last_mut(), kind) {
+            (Some(EventFrame::OpenTag { name, .. }), TreeKind::TagName(tag_name)) => {
+                *name = Some(tag_name);
+            }
+            (Some(EventFrame::Attr(name, _)), TreeKind::AttrName(attr_name)) => {
+                *name = Some(attr_name);
+            }
+            (Some(EventFrame::Attr(_, value)), TreeKind::AttrVal(attr_val)) => {
+                *value = Some(attr_val);
+            }
+            (Some(EventFrame::InnerNode { .. }), TreeKind::TextNode(text))
+            | (Some(EventFrame::InnerNode { .. }), TreeKind::RawTextNode(text)) => {
+                self.handler.text(&text, span);
+            }
+            _ => {}
+        }
+    }
+
+    fn complete_with(&mut self, end: usize, _recovered: bool) {
+        match self.frames.pop() {
+            Some(EventFrame::InnerNode {
+                name: Some(name),
+                close_span,
+            }) => {
+                let span = close_span.unwrap_or_else(|| Span::new(end, end));
+                self.handler.close_tag(&name, span);
+            }
+            Some(EventFrame::OpenTag {
+                start,
+                name: Some(name),
+                attrs,
+            }) => {
+                self.handler.open_tag(&name, &attrs, Span::new(start, end));
+                if let Some(EventFrame::InnerNode { name: open, .. }) = self.frames.last_mut() {
+                    *open = Some(name);
+                }
+            }
+            Some(EventFrame::Attr(Some(name), value)) => {
+                // Attributes are collected by the enclosing open tag
+                let open_tag = self.frames.iter_mut().rev().find_map(|frame| match frame {
+                    EventFrame::OpenTag { attrs, .. } => Some(attrs),
+                    _ => None,
+                });
+                if let Some(attrs) = open_tag {
+                    attrs.push((name, value.map_or(AttrValue::Bare, AttrValue::Text)));
+                }
+            }
+            Some(EventFrame::CloseTag(start)) => {
+                if let Some(EventFrame::InnerNode { close_span, .. }) = self.frames.last_mut() {
+                    *close_span = Some(Span::new(start, end));
+                }
+            }
+            Some(_) => {}
+            None => panic!("no open item to complete"),
+        }
+    }
+
+    fn add_error(&mut self, error: SyntaxError) {
+        self.handler.error(error);
+    }
+
+    fn options(&self) -> &ParseOptions {
+        &self.options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tree::Tree as TypedTree;
+
+    fn parse_src(src: &str) -> ParseResult {
+        parse(src, &ParseOptions::new())
+    }
+
+    #[derive(Default)]
+    struct EventLog(Vec<String>);
+
+    impl EventHandler for EventLog {
+        fn open_tag(&mut self, name: &str, attrs: &Attrs, span: Span) {
+            let names: Vec<&str> = attrs.iter().map(|(name, _)| name.as_str()).collect();
+            self.0.push(format!("open {} {:?} {:?}", name, names, span));
+        }
+
+        fn close_tag(&mut self, name: &str, span: Span) {
+            self.0.push(format!("close {} {:?}", name, span));
+        }
+
+        fn text(&mut self, text: &str, span: Span) {
+            self.0.push(format!("text {:?} {:?}", text, span));
+        }
+
+        fn error(&mut self, error: SyntaxError) {
+            self.0
+                .push(format!("error {} {:?}", error.message, error.span));
+        }
+    }
+
+    #[test]
+    fn events_are_reported_in_document_order() {
+        let mut log = EventLog::default();
+        parse_events(
+            r#"<Doc id="a"><br/>x &amp; y<Code#><b></#Code></Doc>"#,
+            &ParseOptions::new(),
+            &mut log,
+        );
+
+        assert_eq!(
+            log.0,
+            vec![
+                r#"open Doc ["id"] 0..12"#,
+                r#"open br [] 12..17"#,
+                r#"close br 17..17"#,
+                r#"text "x & y" 17..26"#,
+                r#"open Code [] 26..33"#,
+                r#"text "<b>" 33..36"#,
+                r#"close Code 36..44"#,
+                r#"close Doc 44..50"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn event_errors_are_reported() {
+        let mut log = EventLog::default();
+        parse_events("<Doc><p>text</Doc>", &ParseOptions::new(), &mut log);
+
+        assert!(log.0.iter().any(|event| event.starts_with("error ")));
+        assert_eq!(log.0.last().map(String::as_str), Some("close Doc 12..18"));
+    }
+
+    #[test]
+    fn events_follow_parse_options() {
+        let mut log = EventLog::default();
+        parse_events("<Doc ;></Doc>", &ParseOptions::new(), &mut log);
+        assert!(!log
+            .0
+            .contains(&String::from("error unexpected character ';' 5..6")));
+
+        let mut options = ParseOptions::new();
+        options.set_unknown_char_policy(UnknownCharPolicy::Error);
+        let mut log = EventLog::default();
+        parse_events("<Doc ;></Doc>", &options, &mut log);
+        assert!(log
+            .0
+            .contains(&String::from("error unexpected character ';' 5..6")));
+    }
+
+    #[test]
+    fn unclosed_elements_get_synthetic_close_tags() {
+        let src = "<Doc><Title>hi";
+        let result = parse_src(src);
+
+        let errors: Vec<(Span, &str)> = result
+            .errors
+            .iter()
+            .map(|error| (error.span, error.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    Span::new(0, 5),
+                    r#"unclosed "<Doc>" opened here (expected a closing tag, but found EOF)"#
+                ),
+                (
+                    Span::new(5, 12),
+                    r#"unclosed "<Title>" opened here (expected a closing tag, but found EOF)"#
+                ),
+            ]
+        );
+
+        let doc = &result.tree.children[0];
+        assert!(doc.recovered);
+        let close_tag = doc.children.last().unwrap();
+        assert_eq!(close_tag.kind, TreeKind::CloseTag);
+        assert_eq!(close_tag.span, Span::new(src.len(), src.len()));
+
+        let title = &doc.children[1];
+        assert!(title.recovered);
+
+        assert_eq!(
+            TypedTree::from(result.tree).to_string(),
+            "<Doc><Title>hi</Title></Doc>"
+        );
+    }
+
+    #[test]
+    fn closed_elements_are_not_recovered() {
+        let result = parse_src("<Doc></Doc>");
+
+        assert!(result.errors.is_empty());
+        assert!(!result.tree.children[0].recovered);
+    }
+
+    fn resolve_custom(name: &str) -> Option<String> {
+        match name {
+            "mycompany" => Some(String::from("ACME")),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn entities_resolve_through_options() {
+        let src = r#"<Doc by="&mycompany;">&lt;&mycompany;&gt; &unknown;</Doc>"#;
+
+        let mut options = ParseOptions::new();
+        options.set_entity_resolver(resolve_custom);
+        let result = parse(src, &options);
+
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].message, r#"unknown entity "&unknown;""#);
+        assert!(!result.errors[0].is_error());
+        assert_eq!(
+            TypedTree::from(result.tree).to_string(),
+            r#"<Doc by="ACME"><ACME> &unknown;</Doc>"#
+        );
+    }
+
+    #[test]
+    fn raw_text_is_not_decoded() {
+        let result = parse_src("<Code #>&lt;&nope;</# Code>");
+
+        assert!(result.errors.is_empty());
+        assert_eq!(
+            TypedTree::from(result.tree).to_string(),
+            "<Code>&lt;&nope;</Code>"
+        );
+    }
+
+    #[test]
+    fn raw_sections_are_text() {
+        let result = parse_src("<Code>if a <![RAW[<b> &lt;]]> b</Code>");
+
+        assert!(result.errors.is_empty());
+        assert_eq!(
+            TypedTree::from(result.tree).to_string(),
+            "<Code>if a <b> &lt; b</Code>"
+        );
+    }
+
+    fn recovered(src: &str) -> (String, Vec<(Span, String)>) {
+        let result = parse_src(src);
+        let errors = result
+            .errors
+            .iter()
+            .map(|error| (error.span, error.message.clone()))
+            .collect();
+        let direct = crate::tree::parse_to_tree(src, &ParseOptions::new()).unwrap_err();
+        assert_eq!(direct, result.errors);
+
+        (TypedTree::from(result.tree).to_string(), errors)
+    }
+
+    #[test]
+    fn close_tag_for_ancestor_closes_two_levels() {
+        let (tree, errors) = recovered("<Doc><Section><P>text</Section><Footer></Footer></Doc>");
+
+        assert_eq!(
+            tree,
+            "<Doc><Section><P>text</P></Section><Footer></Footer></Doc>"
+        );
+        assert_eq!(
+            errors,
+            vec![(
+                Span::new(23, 30),
+                String::from(
+                    r#"closing tag must match opening (expected "P" but found "Section")"#
+                )
+            )]
+        );
+    }
+
+    #[test]
+    fn close_tag_for_ancestor_closes_three_levels() {
+        let (tree, errors) = recovered("<Doc><A><B><C>x</A><D></D></Doc>");
+
+        assert_eq!(tree, "<Doc><A><B><C>x</C></B></A><D></D></Doc>");
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    Span::new(17, 18),
+                    String::from(r#"closing tag must match opening (expected "C" but found "A")"#)
+                ),
+                (
+                    Span::new(17, 18),
+                    String::from(r#"closing tag must match opening (expected "B" but found "A")"#)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn void_tags_need_no_close_tag() {
+        let src = "<Doc>a<Hr>b<Br/>c<Hr></Hr>d<Br>e</Br></Doc>";
+        let mut options = ParseOptions::new();
+        options.set_void_tags(vec!["Hr", "Br"]);
+        let result = parse(src, &options);
+
+        let errors: Vec<(Span, &str)> = result
+            .errors
+            .iter()
+            .map(|error| (error.span, error.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    Span::new(23, 25),
+                    r#"void element "Hr" can't have content or a closing tag"#
+                ),
+                (
+                    Span::new(34, 36),
+                    r#"void element "Br" can't have content or a closing tag"#
+                ),
+            ]
+        );
+        assert_eq!(
+            TypedTree::from(result.tree).to_string(),
+            "<Doc>a<Hr></Hr>b<Br></Br>c<Hr></Hr>d<Br></Br>e</Doc>"
+        );
+    }
+
+    #[test]
+    fn comments_can_be_skipped() {
+        let src = "<Doc>a<!-- note -->b</Doc>";
+        let mut options = ParseOptions::new();
+
+        let kept = parse(src, &options);
+        assert_eq!(
+            TypedTree::from(kept.tree).to_string(),
+            "<Doc>a<!-- note -->b</Doc>"
+        );
+
+        options.set_skip_comments(true);
+        let skipped = parse(src, &options);
+        assert!(skipped.errors.is_empty());
+        assert_eq!(TypedTree::from(skipped.tree).to_string(), "<Doc>ab</Doc>");
+    }
+
+    #[test]
+    fn unicode_names() {
+        let src = r#"<Überschrift größe="2"><章节 标题="一">x</章节></Überschrift>"#;
+        let result = parse_src(src);
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+
+        let open_tag = &result.tree.children[0].children[0];
+        let tag_name = &open_tag.children[0];
+        assert_eq!(
+            tag_name.kind,
+            TreeKind::TagName(String::from("Überschrift"))
+        );
+        assert_eq!(&src[tag_name.span.start..tag_name.span.end], "Überschrift");
+        let attr_name = &open_tag.children[1].children[0].children[0];
+        assert_eq!(&src[attr_name.span.start..attr_name.span.end], "größe");
+
+        assert_eq!(TypedTree::from(result.tree).to_string(), src);
+    }
+
+    #[test]
+    fn orphaned_hashes_are_counted_and_located() {
+        let errors = parse_src("<Doc>x</## Doc>").errors;
+        assert_eq!(errors[0].span, Span::new(8, 10));
+        assert_eq!(
+            errors[0].message,
+            r###"orphaned hashes (found 2 '#' not part of a fence; expected "##>" to end an open tag, or "</##" to close an element fenced with them)"###
+        );
+
+        let errors = parse_src("<Doc # x>y</Doc>").errors;
+        assert!(errors.iter().any(|error| error.span == Span::new(5, 6)
+            && error.message.starts_with("orphaned hashes (found 1 '#'")));
+    }
+
+    #[test]
+    fn misplaced_colons_in_tag_names() {
+        let messages = |src| -> Vec<(Span, String)> {
+            parse_src(src)
+                .errors
+                .into_iter()
+                .map(|error| (error.span, error.message))
+                .collect()
+        };
+
+        assert_eq!(messages("<a:b>x</a:b>"), vec![]);
+        assert_eq!(
+            messages("<:Foo></:Foo>"),
+            vec![
+                (
+                    Span::new(1, 5),
+                    String::from("expected a prefix before ':'")
+                ),
+                (
+                    Span::new(8, 12),
+                    String::from("expected a prefix before ':'")
+                ),
+            ]
+        );
+        assert_eq!(
+            messages("<Foo:>x</Foo:>"),
+            vec![
+                (Span::new(1, 5), String::from("expected a name after ':'")),
+                (Span::new(9, 13), String::from("expected a name after ':'")),
+            ]
+        );
+        assert_eq!(
+            messages("<a:b>x</a:c>")[0].1,
+            "closing tag must match opening (expected \"a:b\" but found \"a:c\")"
+        );
+    }
+
+    #[test]
+    fn unknown_char_in_tag_is_reported() {
+        let src = r#"<Doc ; title="x"></Doc>"#;
+
+        let mut options = ParseOptions::new();
+        options.set_unknown_char_policy(UnknownCharPolicy::Error);
+        let result = parse(src, &options);
+
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].span, Span::new(5, 6));
+        assert_eq!(result.errors[0].message, "unexpected character ';'");
+        assert_eq!(
+            TypedTree::from(result.tree).to_string(),
+            r#"<Doc title="x"></Doc>"#
+        );
+    }
+
+    #[test]
+    fn overlong_attr_value_is_an_error() {
+        let src = r#"<Img src="data:image/png;base64,AAAA"></Img>"#;
+
+        let mut options = ParseOptions::new();
+        options.set_max_attr_value_len(10, AttrLengthPolicy::Error);
+        let result = parse(src, &options);
+
+        assert_eq!(result.errors.len(), 1);
+        assert!(result.errors[0].is_error());
+        assert_eq!(result.errors[0].span, Span::new(5, 36));
+        assert_eq!(
+            result.errors[0].message,
+            "attribute value is longer than 10 bytes"
+        );
+    }
+
+    #[test]
+    fn overlong_attr_value_is_truncated() {
+        let src = r#"<Img alt="café au lait" src="x"></Img>"#;
+
+        let mut options = ParseOptions::new();
+        options.set_max_attr_value_len(4, AttrLengthPolicy::Truncate);
+        let result = parse(src, &options);
+
+        assert_eq!(result.errors.len(), 1);
+        assert!(!result.errors[0].is_error());
+        assert_eq!(result.errors[0].span, Span::new(5, 23));
+        assert_eq!(
+            TypedTree::from(result.tree).to_string(),
+            r#"<Img alt="caf" src="x"></Img>"#
+        );
+    }
+
+    #[test]
+    fn reparsed_element_has_absolute_spans() {
+        let src = "<Doc>\n  <Title lang=\"en\">My first doc</Title>\n</Doc>";
+        let full = parse_src(src);
+        let title = &full.tree.children[0].children[2];
+        assert_eq!(title.kind, TreeKind::InnerNode);
+
+        let result = reparse_element(src, title.span, &ParseOptions::new());
+
+        assert!(result.errors.is_empty());
+        assert_eq!(result.tree, *title);
+        assert_eq!(result.tree.span, Span::new(8, 45));
+        let tag_name = &result.tree.children[0].children[0];
+        assert_eq!(tag_name.kind, TreeKind::TagName(String::from("Title")));
+        assert_eq!(tag_name.span, Span::new(9, 14));
+    }
+
+    #[test]
+    fn reparse_errors_have_absolute_spans() {
+        let src = "<Doc><Title>hi</Doc>";
+        let result = reparse_element(src, Span::new(5, 14), &ParseOptions::new());
+
+        assert!(result.tree.recovered);
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].span, Span::new(5, 12));
+    }
+
+    #[test]
+    fn reparse_reports_bad_spans() {
+        let src = "<Doc>café</Doc>";
+
+        for span in [Span::new(5, 40), Span::new(6, 3), Span::new(0, 9)] {
+            let result = reparse_element(src, span, &ParseOptions::new());
+
+            assert!(result.tree.recovered);
+            assert_eq!(result.errors.len(), 1);
+            assert_eq!(
+                result.errors[0].message,
+                format!(
+                    "span {}..{} isn't a range of whole characters in the source",
+                    span.start, span.end
+                )
+            );
+            assert_eq!(result.errors[0].span, Span::new(16, 16));
+            assert!(result.errors[0].to_string().contains("span "));
+        }
+    }
+
+    #[test]
+    fn errors_are_sorted_by_span() {
+        let src = "<Doc ;><Title></Doc>";
+        let mut options = ParseOptions::new();
+        options.set_unknown_char_policy(UnknownCharPolicy::Error);
+
+        let result = parse(src, &options);
+
+        assert!(result.errors.len() > 1);
+        assert_eq!(result.errors[0].message, "unexpected character ';'");
+        assert!(result
+            .errors
+            .windows(2)
+            .all(|pair| pair[0].span <= pair[1].span));
+    }
+
+    #[test]
+    fn elements_beyond_max_depth_are_skipped() {
+        let mut options = ParseOptions::new();
+        options.set_max_depth(2);
+        options.set_void_tags(["Br"]);
+
+        let result = parse("<Doc><A>a</A><B><C><D/><Br>c</C></B>b</Doc>", &options);
+        let errors: Vec<(Span, &str)> = result
+            .errors
+            .iter()
+            .map(|error| (error.span, error.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![(Span::new(16, 32), "maximum nesting depth 2 exceeded")]
+        );
+        assert_eq!(
+            TypedTree::from(result.tree).to_string(),
+            "<Doc><A>a</A><B></B>b</Doc>"
+        );
+    }
+
+    #[test]
+    fn deep_nesting_does_not_overflow() {
+        let src = format!("{}{}", "<A>".repeat(100_000), "</A>".repeat(100_000));
+        let result = parse_src(&src);
+
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(
+            result.errors[0].message,
+            "maximum nesting depth 256 exceeded"
+        );
+    }
+
+    #[test]
+    fn attribute_value_elements_count_towards_max_depth() {
+        let mut options = ParseOptions::new();
+        options.set_element_attr_values(true);
+        options.set_max_depth(2);
+
+        let result = parse("<Doc><Cell v=<b w=<i/>/>/></Doc>", &options);
+        let errors: Vec<(Span, &str)> = result
+            .errors
+            .iter()
+            .map(|error| (error.span, error.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![(Span::new(13, 24), "maximum nesting depth 2 exceeded")]
+        );
+        assert_eq!(
+            TypedTree::from(result.tree).to_string(),
+            "<Doc><Cell v></Cell></Doc>"
+        );
+
+        options.set_max_depth(256);
+        let src = format!("{}<A/>{}", "<A x=".repeat(100_000), "/>".repeat(100_000));
+        let result = parse(&src, &options);
+
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(
+            result.errors[0].message,
+            "maximum nesting depth 256 exceeded"
+        );
+    }
+
+    #[test]
+    fn documents_have_a_single_root() {
+        let result = parse_src("  ");
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].message, "unexpected EOF");
+
+        let result = parse_src("<Doc>a</Doc>\n");
+        assert!(result.errors.is_empty());
+
+        let result = parse_src("<Doc>a</Doc>\n<Extra>b</Extra>\n");
+        let errors: Vec<(Span, &str)> = result
+            .errors
+            .iter()
+            .map(|error| (error.span, error.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![(
+                Span::new(13, 29),
+                "documents must have a single root element"
+            )]
+        );
+        assert_eq!(TypedTree::from(result.tree).to_string(), "<Doc>a</Doc>");
+    }
+
+    #[test]
+    fn text_before_the_root_is_reported() {
+        let result = parse_src("\n  <Doc>a</Doc>");
+        assert!(result.errors.is_empty());
+
+        let result = parse_src("Notes:  \n<Doc>a</Doc>");
+        assert_eq!(
+            result.errors,
+            vec![SyntaxError::warning(
+                Span::new(0, 6),
+                "text before the root element is ignored"
+            )]
+        );
+        assert_eq!(TypedTree::from(result.tree).to_string(), "<Doc>a</Doc>");
+    }
+
+    #[test]
+    fn clean_and_truncated_eof() {
+        let result = parse_src(r#"<Doc><Title lang="en">hi</Title></Doc>"#);
+        assert!(result.errors.is_empty());
+        assert!(!result.is_truncated());
+
+        let src = r#"<Doc><Title lang="en""#;
+        let result = parse_src(src);
+        assert!(result.is_truncated());
+        assert_eq!(result.errors[0].kind, ErrorKind::UnexpectedEof);
+        assert!(result
+            .errors
+            .iter()
+            .any(|error| error.span == Span::new(src.len(), src.len())));
+
+        let result = parse_src("<Doc></Title></Doc>");
+        assert!(!result.errors.is_empty());
+        assert!(!result.is_truncated());
+    }
+
+    #[test]
+    fn self_closing_tags() {
+        let src = r#"<Doc>a<br/>b<Img src="x.png" /></Doc>"#;
+        let result = parse_src(src);
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+
+        let br = &result.tree.children[0].children[2];
+        assert_eq!(br.kind, TreeKind::InnerNode);
+        assert_eq!(br.span, Span::new(6, 11));
+        assert_eq!(br.children.len(), 1);
+
+        assert_eq!(
+            TypedTree::from(result.tree).to_string(),
+            r#"<Doc>a<br></br>b<Img src="x.png"></Img></Doc>"#
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let src = r#"<!-- header --><Doc title="a-->b"><!-- TODO --></Doc><!-- footer -->"#;
+        let result = parse_src(src);
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            TypedTree::from(result.tree).to_string(),
+            r#"<Doc title="a-->b"><!-- TODO --></Doc>"#
+        );
+
+        let src = "<Doc>a<!-- b</Doc>";
+        let result = parse_src(src);
+        let error = result
+            .errors
+            .iter()
+            .find(|error| error.message == "unterminated comment")
+            .unwrap();
+        assert_eq!(error.span, Span::new(6, 10));
+    }
+
+    #[test]
+    fn attr_value_escapes() {
+        let src = r#"<Doc a="say \"hi\"" b="x\\y\tz\n" c="\q &amp;"></Doc>"#;
+        let result = parse_src(src);
+
+        assert_eq!(result.errors.len(), 1);
+        assert!(!result.errors[0].is_error());
+        assert_eq!(result.errors[0].message, r#"unknown escape sequence "\q""#);
+        assert_eq!(result.errors[0].span, Span::new(37, 39));
+
+        match TypedTree::from(result.tree) {
+            TypedTree::Inner { attrs, .. } => {
+                let values: Vec<&str> = attrs.iter().map(|(_, v)| v.as_text().unwrap()).collect();
+                assert_eq!(values, vec![r#"say "hi""#, "x\\y\tz\n", r"\q &"]);
+            }
+            _ => panic!("expected inner node"),
+        }
+    }
+
+    #[test]
+    fn boolean_attributes() {
+        let src = r#"<Form><Input disabled type="text" required/><Input checked></Input></Form>"#;
+        let result = parse_src(src);
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+
+        let input = &result.tree.children[0].children[1];
+        let attrs = &input.children[0].children[1];
+        assert_eq!(attrs.children.len(), 3);
+        assert_eq!(attrs.children[0].span, Span::new(13, 21));
+        assert_eq!(attrs.children[0].children.len(), 1);
+
+        assert_eq!(
+            TypedTree::from(result.tree).to_string(),
+            r#"<Form><Input disabled type="text" required></Input><Input checked></Input></Form>"#
+        );
+    }
+
+    #[test]
+    fn duplicate_attributes() {
+        let result = parse_src(r#"<Doc title="a" title="b"></Doc>"#);
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].message, r#"duplicate attribute "title""#);
+        assert_eq!(result.errors[0].span, Span::new(15, 20));
+        assert_eq!(
+            TypedTree::from(result.tree).to_string(),
+            r#"<Doc title="a"></Doc>"#
+        );
+
+        let result = parse_src(r#"<Doc x="1" y="2" x="3" x="4"></Doc>"#);
+        let spans: Vec<Span> = result.errors.iter().map(|error| error.span).collect();
+        assert_eq!(spans, vec![Span::new(17, 18), Span::new(23, 24)]);
+        assert_eq!(
+            TypedTree::from(result.tree).to_string(),
+            r#"<Doc x="1" y="2"></Doc>"#
+        );
+    }
+
+    #[test]
+    fn dotted_and_namespaced_attribute_names() {
+        let result = parse_src(r#"<Doc xmlns:svg="u" data.id="x" aria-label_2="y"></Doc>"#);
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        match TypedTree::from(result.tree) {
+            TypedTree::Inner { attrs, .. } => {
+                let names: Vec<&str> = attrs.iter().map(|(name, _)| name.as_str()).collect();
+                assert_eq!(names, vec!["xmlns:svg", "data.id", "aria-label_2"]);
+            }
+            _ => panic!("expected inner node"),
+        }
+
+        let result = parse_src(r#"<Doc :-.="x" svg:="y"></Doc>"#);
+        let errors: Vec<(Span, &str)> = result
+            .errors
+            .iter()
+            .map(|error| (error.span, error.message.as_str()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (Span::new(5, 8), "expected a prefix before ':'"),
+                (Span::new(13, 17), "expected a name after ':'"),
+            ]
+        );
+
+        // Names can't start with other punctuation at all
+        assert!(parse_src(r#"<Doc .="x"></Doc>"#)
+            .errors
+            .iter()
+            .any(SyntaxError::is_error));
+    }
+
+    #[test]
+    fn name_chars_are_configurable() {
+        let src = r#"<Doc @click="go" $ref="a"></Doc>"#;
+        assert!(!parse_src(src).errors.is_empty());
+
+        let mut options = ParseOptions::new();
+        options.set_name_chars(NameChars {
+            start: |c| c.is_alphabetic() || c == '@' || c == '$',
+            rest: char::is_alphanumeric,
+        });
+        let result = parse(src, &options);
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        assert_eq!(
+            TypedTree::from(result.tree).to_string(),
+            r#"<Doc @click="go" $ref="a"></Doc>"#
+        );
+
+        // Without '.', a qualified tag name is no longer a name
+        let result = parse("<Code.Racket></Code.Racket>", &options);
+        assert!(!result.errors.is_empty());
+
+        // A name needn't contain a letter, if the characters it does contain
+        // are allowed
+        let result = parse(r#"<Doc @="go"></Doc>"#, &options);
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+
+        // But `_` is reserved, even when it's allowed in names
+        options.set_name_chars(NameChars {
+            start: |c| c.is_alphabetic() || c == '_',
+            rest: char::is_alphanumeric,
+        });
+        let result = parse(r#"<Img _="x" _id="y"></Img>"#, &options);
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].span, Span::new(5, 6));
+        assert_eq!(
+            result.errors[0].message,
+            r#"attribute name "_" is reserved for positional attributes"#
+        );
+    }
+
+    #[test]
+    fn positional_attributes() {
+        let src = r#"<Img "photo.jpg" alt="A &amp; B"></Img>"#;
+        let result = parse_src(src);
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+
+        let tree = TypedTree::from(result.tree);
+        assert_eq!(tree.to_string(), r#"<Img "photo.jpg" alt="A & B"></Img>"#);
+        match tree {
+            TypedTree::Inner { attrs, .. } => {
+                assert_eq!(attrs[0].0, POSITIONAL_ATTR);
+                assert_eq!(attrs[0].1.as_text(), Some("photo.jpg"));
+            }
+            _ => panic!("expected inner node"),
+        }
+
+        let result = parse_src(r#"<Img "a" "b"></Img>"#);
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(
+            result.errors[0].message,
+            "only one attribute can be positional"
+        );
+        assert_eq!(result.errors[0].span, Span::new(10, 11));
+    }
+}
@@ -1,49 +1,1499 @@
-use super::tree::{Attrs, Tree};
-use std::collections::HashMap;
+use super::common::Span;
+use super::file::SourceFile;
+use super::schema::{ComponentSchema, ValidationError};
+use super::syntax_error::fmt_source;
+use super::tree::{AttrValue, Attrs, AttrsExt, Tree};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
 
-pub struct Processor {
-    transforms: HashMap<String, Transform>,
+/// Applies registered transforms to a tree, bottom-up.
+///
+/// When looking up the transform for a tag, an exact match on the tag name
+/// takes precedence over a prefix transform registered for the part of the
+/// name before the first `:` (so `svg:rect` is handled by a transform
+/// registered for `svg:rect` if there is one, and by the `svg` prefix
+/// transform otherwise). Tags matching neither are passed to the default
+/// transform, if there is one, and through unchanged otherwise.
+///
+/// A transform's output is processed in turn (its children having already
+/// been processed), so a transform may produce elements that other
+/// transforms handle. Transforms registered with `add_terminal_transform`
+/// don't have their output processed, though, which allows a transform to
+/// produce an element it's registered for. Otherwise, transforms that keep
+/// producing each other's elements (a `Doc` transform producing a
+/// `<Section>`, whose transform produces a `<Doc>`, and so on) make
+/// processing fail with an error naming the cycle.
+///
+/// Transforms can share state through a context of type `Ctx`, which is
+/// passed to `process_with` and lent to each transform in turn (see
+/// `add_context_transform`).
+///
+/// Cloning a processor is cheap: the transforms themselves are shared, but
+/// each clone has its own table, so registering a transform on one doesn't
+/// affect the other.
+pub struct Processor<Ctx = ()> {
+    transforms: HashMap<String, Transform<Ctx>>,
+    prefix_transforms: HashMap<String, PrefixTransform<Ctx>>,
+    default_transform: Option<PrefixTransform<Ctx>>,
+    schemas: HashMap<String, ComponentSchema>,
+    dedent_tags: HashSet<String>,
+    // The tags whose transform's output isn't processed again
+    terminal_tags: HashSet<String>,
+    data: Option<Data>,
 }
 
-pub type Transform = fn(Attrs, Vec<Tree>) -> Tree;
+// The most transforms that can produce one another's output before
+// processing fails, assuming that they're stuck in a cycle
+const MAX_TRANSFORM_DEPTH: usize = 64;
+
+/// Named collections of rows for `<Each>` to iterate over. Each row maps
+/// field names to values.
+pub type Data = HashMap<String, Vec<HashMap<String, String>>>;
+
+/// A transform, as registered with any of the `add_*transform` methods. It
+/// replaces an element with any number of trees, or fails.
+pub type Transform<Ctx = ()> =
+    Rc<dyn Fn(&mut Ctx, Attrs, Vec<Tree>) -> Result<Vec<Tree>, ProcessError>>;
+
+/// A transform for every tag in a namespace. It receives the local name (the
+/// part of the tag name following the prefix and `:`).
+pub type PrefixTransform<Ctx = ()> =
+    Rc<dyn Fn(&mut Ctx, &str, Attrs, Vec<Tree>) -> Result<Vec<Tree>, ProcessError>>;
 
 impl Processor {
     pub fn new() -> Self {
+        Processor::with_context()
+    }
+}
+
+impl<Ctx> Processor<Ctx> {
+    /// Creates a processor whose transforms share a context of type `Ctx`.
+    /// (`Processor::new` creates one without a context.)
+    pub fn with_context() -> Self {
         Processor {
             transforms: HashMap::new(),
+            prefix_transforms: HashMap::new(),
+            default_transform: None,
+            schemas: HashMap::new(),
+            dedent_tags: HashSet::new(),
+            terminal_tags: HashSet::new(),
+            data: None,
+        }
+    }
+
+    pub fn add_transform<S, F>(&mut self, name: S, transform: F)
+    where
+        S: Into<String>,
+        F: Fn(Attrs, Vec<Tree>) -> Tree + 'static,
+    {
+        self.add_context_transform(name, move |_: &mut Ctx, attrs, children| {
+            Ok(vec![transform(attrs, children)])
+        });
+    }
+
+    /// Registers one transform for several tags. The transform receives the
+    /// name of the tag it's applied to.
+    pub fn add_transform_for<I, S, F>(&mut self, names: I, transform: F)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+        F: Fn(&str, Attrs, Vec<Tree>) -> Tree + 'static,
+    {
+        let transform = Rc::new(transform);
+        for name in names {
+            let name = name.into();
+            let tag = name.clone();
+            let transform = Rc::clone(&transform);
+            self.add_context_transform(name, move |_: &mut Ctx, attrs, children| {
+                Ok(vec![transform(&tag, attrs, children)])
+            });
         }
     }
 
-    pub fn add_transform<S>(&mut self, name: S, transform: Transform)
+    /// Registers a transform that may fail, causing processing to fail with
+    /// a `ProcessError`.
+    pub fn add_try_transform<S, F>(&mut self, name: S, transform: F)
+    where
+        S: Into<String>,
+        F: Fn(Attrs, Vec<Tree>) -> Result<Tree, String> + 'static,
+    {
+        let name = name.into();
+        let tag = name.clone();
+        self.add_context_transform(name, move |_: &mut Ctx, attrs, children| {
+            transform(attrs, children)
+                .map(|tree| vec![tree])
+                .map_err(|message| ProcessError::new(tag.clone(), message))
+        });
+    }
+
+    /// Registers the most general kind of transform: one that can use and
+    /// update the context, replace the element with a fragment (any number
+    /// of trees, including none), and fail. The other `add_*transform`
+    /// methods are shorthands for this one.
+    ///
+    /// If the transform fails with an error without a span, it's given the
+    /// element's span.
+    pub fn add_context_transform<S, F>(&mut self, name: S, transform: F)
+    where
+        S: Into<String>,
+        F: Fn(&mut Ctx, Attrs, Vec<Tree>) -> Result<Vec<Tree>, ProcessError> + 'static,
+    {
+        let name = name.into();
+        self.terminal_tags.remove(&name);
+        self.transforms.insert(name, Rc::new(transform));
+    }
+
+    /// Registers a transform whose output is inserted as it is, rather than
+    /// processed again like other transforms' output. This allows a
+    /// transform to produce elements that are themselves transformed, like
+    /// a `<p>` transform producing a `<p class="...">`. The output isn't
+    /// processed again when enclosing elements are transformed either,
+    /// provided their transforms keep it as it is (rather than modifying or
+    /// rebuilding it).
+    pub fn add_terminal_transform<S, F>(&mut self, name: S, transform: F)
+    where
+        S: Into<String>,
+        F: Fn(Attrs, Vec<Tree>) -> Tree + 'static,
+    {
+        let name = name.into();
+        self.add_transform(name.clone(), transform);
+        self.terminal_tags.insert(name);
+    }
+
+    /// Registers a transform for all `prefix:*` tags.
+    pub fn add_prefix_transform<S, F>(&mut self, prefix: S, transform: F)
     where
         S: Into<String>,
+        F: Fn(&str, Attrs, Vec<Tree>) -> Tree + 'static,
+    {
+        self.prefix_transforms.insert(
+            prefix.into(),
+            Rc::new(move |_: &mut Ctx, local_name: &str, attrs, children| {
+                Ok(vec![transform(local_name, attrs, children)])
+            }),
+        );
+    }
+
+    /// Sets the transform for tags without a transform of their own. It
+    /// receives the tag name, and its output is reprocessed like any
+    /// transform's. Elements that transforms (including this one) produce
+    /// aren't passed to it, though, so that it can wrap an element in a
+    /// `<div>` without then wrapping that `<div>`, and so on.
+    pub fn set_default_transform<F>(&mut self, transform: F)
+    where
+        F: Fn(&str, Attrs, Vec<Tree>) -> Tree + 'static,
     {
-        self.transforms.insert(name.into(), transform);
+        self.default_transform = Some(Rc::new(
+            move |_: &mut Ctx, tag_name: &str, attrs, children| {
+                Ok(vec![transform(tag_name, attrs, children)])
+            },
+        ));
     }
 
-    pub fn process(&self, tree: Tree) -> Tree {
+    /// Removes the common leading whitespace from the lines of each text
+    /// child of `name` elements (before they're transformed), so that code
+    /// indented to match the surrounding markup isn't rendered indented.
+    /// Lines containing only whitespace are emptied, and don't count towards
+    /// the common indentation.
+    pub fn add_dedent<S>(&mut self, name: S)
+    where
+        S: Into<String>,
+    {
+        self.dedent_tags.insert(name.into());
+    }
+
+    /// Enables `<Each>` elements, which repeat their content once for each
+    /// row of a collection in `data`:
+    ///
+    /// ```text
+    /// <Each over="rows" as="row"><li>{row.name}</li></Each>
+    /// ```
+    ///
+    /// The `over` attribute names the collection, and `as` names the
+    /// variable each row is bound to (`item` if omitted). In each copy of
+    /// the content, placeholders of the form `{row.field}` in text and in
+    /// attribute values are replaced with the row's value for `field`.
+    /// Placeholders for other variables are left alone, so that `<Each>`
+    /// elements can be nested. The `<Each>` element itself is replaced by the
+    /// copies, which are then processed as usual.
+    pub fn set_data(&mut self, data: Data) {
+        self.data = Some(data);
+    }
+
+    pub fn add_schema<S>(&mut self, name: S, schema: ComponentSchema)
+    where
+        S: Into<String>,
+    {
+        self.schemas.insert(name.into(), schema);
+    }
+
+    /// Checks every element of `tree` that has a registered schema against
+    /// it. This is meant to be done before processing, as transforms needn't
+    /// produce elements matching the schemas.
+    pub fn validate(&self, tree: &Tree) -> Vec<ValidationError> {
+        let mut errors = vec![];
+        self.validate_into(tree, &mut errors);
+        errors
+    }
+
+    fn validate_into(&self, tree: &Tree, errors: &mut Vec<ValidationError>) {
+        if let Tree::Inner {
+            tag_name, children, ..
+        } = tree
+        {
+            if let Some(schema) = self.schemas.get(tag_name) {
+                schema.check(tree, errors);
+            }
+
+            for child in children {
+                self.validate_into(child, errors);
+            }
+        }
+    }
+
+    /// Processes `tree`, lending `ctx` to each transform applied. The root
+    /// must be transformed into exactly one tree (`process_fragment_with`
+    /// has no such restriction).
+    pub fn process_with(&self, ctx: &mut Ctx, tree: Tree) -> Result<Tree, ProcessError> {
+        self.process_root(ctx, tree, &mut None)
+    }
+
+    fn process_root(
+        &self,
+        ctx: &mut Ctx,
+        tree: Tree,
+        trace: &mut Option<&mut Vec<TraceEvent>>,
+    ) -> Result<Tree, ProcessError> {
+        let (tag, span) = match &tree {
+            Tree::Inner { tag_name, span, .. } => (tag_name.clone(), *span),
+            _ => (String::new(), None),
+        };
+
+        let mut trees = self.process_traced(ctx, tree, trace, &mut vec![], &HashSet::new())?;
+        if trees.len() != 1 {
+            return Err(ProcessError {
+                span,
+                ..ProcessError::new(
+                    tag,
+                    format!("expected a single root, but found {} trees", trees.len()),
+                )
+            });
+        }
+        Ok(trees.pop().unwrap())
+    }
+
+    /// Processes each root of a fragment (a sequence of sibling trees),
+    /// lending `ctx` to each transform applied.
+    pub fn process_fragment_with(
+        &self,
+        ctx: &mut Ctx,
+        trees: Vec<Tree>,
+    ) -> Result<Vec<Tree>, ProcessError> {
+        self.process_siblings(ctx, trees, &mut None, &mut vec![], &HashSet::new())
+    }
+
+    /// Processes each of `trees` as `process_with` would, lending `ctx` to
+    /// each transform applied. Unlike with `process_fragment_with`, each tree
+    /// must be transformed into exactly one tree, so the results line up with
+    /// `trees`.
+    pub fn process_all_with(
+        &self,
+        ctx: &mut Ctx,
+        trees: Vec<Tree>,
+    ) -> Result<Vec<Tree>, ProcessError> {
+        trees
+            .into_iter()
+            .map(|tree| self.process_root(ctx, tree, &mut None))
+            .collect()
+    }
+
+    // Processes a sequence of sibling trees, concatenating their results.
+    // Elements in `done` have already been processed, and are kept as they are.
+    fn process_siblings(
+        &self,
+        ctx: &mut Ctx,
+        trees: Vec<Tree>,
+        trace: &mut Option<&mut Vec<TraceEvent>>,
+        chain: &mut Vec<String>,
+        done: &HashSet<ElementId>,
+    ) -> Result<Vec<Tree>, ProcessError> {
+        let mut out = vec![];
+        for tree in trees {
+            if ElementId::of(&tree).is_some_and(|id| done.contains(&id)) {
+                out.push(tree);
+            } else {
+                out.extend(self.process_traced(ctx, tree, trace, chain, done)?);
+            }
+        }
+        Ok(out)
+    }
+
+    fn process_traced(
+        &self,
+        ctx: &mut Ctx,
+        tree: Tree,
+        trace: &mut Option<&mut Vec<TraceEvent>>,
+        // The tags whose transforms produced `tree`, outermost first. The
+        // default transform only applies when this is empty, since it
+        // doesn't apply to transforms' output.
+        chain: &mut Vec<String>,
+        // The children given to the transform that produced `tree`, which
+        // aren't processed again when they're part of its output
+        done: &HashSet<ElementId>,
+    ) -> Result<Vec<Tree>, ProcessError> {
         match tree {
-            Tree::Text(_) => tree,
+            Tree::Text { .. } | Tree::PreText { .. } | Tree::Comment { .. } => Ok(vec![tree]),
             Tree::Inner {
                 tag_name,
                 attrs,
                 children,
+                span,
+            } if tag_name == "Each" && self.data.is_some() => {
+                let copies =
+                    self.expand_each(&attrs, children)
+                        .map_err(|message| ProcessError {
+                            span,
+                            ..ProcessError::new(tag_name, message)
+                        })?;
+
+                self.process_siblings(ctx, copies, trace, chain, done)
+            }
+            Tree::Inner {
+                tag_name,
+                attrs,
+                children,
+                span,
             } => {
-                let children = children
-                    .into_iter()
-                    .map(|child| self.process(child))
-                    .collect::<Vec<Tree>>();
+                let processed = self.process_siblings(ctx, children, trace, chain, done)?;
+                let children = if self.dedent_tags.contains(&tag_name) {
+                    processed
+                        .into_iter()
+                        .map(|child| match child {
+                            Tree::Text { text, span } => Tree::Text {
+                                text: dedent(&text),
+                                span,
+                            },
+                            Tree::PreText { text, span } => Tree::PreText {
+                                text: dedent(&text),
+                                span,
+                            },
+                            child => child,
+                        })
+                        .collect()
+                } else {
+                    processed
+                };
+                let child_count = children.len();
 
-                if let Some(transform) = self.transforms.get(&tag_name) {
-                    self.process(transform(attrs, children))
+                // The transform's output is processed in turn, except for
+                // the children it keeps (which have just been processed)
+                let given = children.iter().filter_map(ElementId::of).collect();
+                let out = if let Some(transform) = self.transforms.get(&tag_name) {
+                    transform(ctx, attrs, children)
+                } else if let Some((transform, local_name)) = self.find_prefix_transform(&tag_name)
+                {
+                    transform(ctx, local_name, attrs, children)
+                } else if let (Some(transform), true) = (&self.default_transform, chain.is_empty())
+                {
+                    transform(ctx, &tag_name, attrs, children)
                 } else {
-                    Tree::Inner {
+                    if let Some(events) = trace {
+                        events.push(TraceEvent::PassedThrough {
+                            tag: tag_name.clone(),
+                        });
+                    }
+
+                    return Ok(vec![Tree::Inner {
                         tag_name,
                         attrs,
                         children,
+                        span,
+                    }]);
+                };
+
+                let out = out.map_err(|mut error| {
+                    if error.span.is_none() {
+                        error.span = span;
                     }
+                    error
+                })?;
+
+                if let Some(events) = trace {
+                    events.push(TraceEvent::Transformed {
+                        tag: tag_name.clone(),
+                        from_children: child_count,
+                        to: match &out[..] {
+                            [Tree::Inner { tag_name, .. }] => Some(tag_name.clone()),
+                            _ => None,
+                        },
+                    });
+                }
+
+                if self.terminal_tags.contains(&tag_name) {
+                    return Ok(out);
+                }
+
+                if chain.len() == MAX_TRANSFORM_DEPTH {
+                    return Err(ProcessError {
+                        span,
+                        ..ProcessError::new(tag_name.clone(), transform_cycle(chain, &tag_name))
+                    });
                 }
+
+                chain.push(tag_name);
+                let reprocessed = self.process_siblings(ctx, out, trace, chain, &given)?;
+                chain.pop();
+                Ok(reprocessed)
+            }
+        }
+    }
+
+    fn expand_each(&self, attrs: &Attrs, children: Vec<Tree>) -> Result<Vec<Tree>, String> {
+        let over = attrs.get_text("over").ok_or("missing attribute 'over'")?;
+        let var = attrs.get_text("as").unwrap_or("item");
+        let rows = self
+            .data
+            .as_ref()
+            .and_then(|data| data.get(over))
+            .ok_or_else(|| format!("no data named '{}'", over))?;
+
+        let mut copies = vec![];
+        for row in rows {
+            for child in &children {
+                copies.push(substitute(child.clone(), var, row)?);
+            }
+        }
+        Ok(copies)
+    }
+
+    /// Adds everything registered with `other` (transforms, the default
+    /// transform, schemas, dedented tags, and data collections) to this
+    /// processor. If both have something
+    /// registered under the same name, `on_conflict` decides what happens:
+    /// either `other`'s registration replaces this one's, or nothing is
+    /// merged and the conflicting names are returned in an error.
+    pub fn merge(
+        &mut self,
+        other: Processor<Ctx>,
+        on_conflict: OnConflict,
+    ) -> Result<(), MergeError> {
+        if on_conflict == OnConflict::Error {
+            let mut conflicts: Vec<String> = other
+                .transforms
+                .keys()
+                .filter(|name| self.transforms.contains_key(*name))
+                .cloned()
+                .chain(
+                    other
+                        .prefix_transforms
+                        .keys()
+                        .filter(|prefix| self.prefix_transforms.contains_key(*prefix))
+                        .map(|prefix| format!("{}:*", prefix)),
+                )
+                .chain(
+                    other
+                        .schemas
+                        .keys()
+                        .filter(|name| self.schemas.contains_key(*name))
+                        .map(|name| format!("schema for {}", name)),
+                )
+                .collect();
+            if self.default_transform.is_some() && other.default_transform.is_some() {
+                conflicts.push(String::from("default transform"));
+            }
+            if let (Some(data), Some(other_data)) = (&self.data, &other.data) {
+                conflicts.extend(
+                    other_data
+                        .keys()
+                        .filter(|name| data.contains_key(*name))
+                        .map(|name| format!("data named {}", name)),
+                );
+            }
+
+            if !conflicts.is_empty() {
+                conflicts.sort();
+                return Err(MergeError { conflicts });
+            }
+        }
+
+        for name in other.transforms.keys() {
+            self.terminal_tags.remove(name);
+        }
+        self.terminal_tags.extend(other.terminal_tags);
+        self.transforms.extend(other.transforms);
+        self.prefix_transforms.extend(other.prefix_transforms);
+        if other.default_transform.is_some() {
+            self.default_transform = other.default_transform;
+        }
+        self.schemas.extend(other.schemas);
+        self.dedent_tags.extend(other.dedent_tags);
+        if let Some(other_data) = other.data {
+            self.data.get_or_insert_with(Data::new).extend(other_data);
+        }
+        Ok(())
+    }
+
+    // Whether processing does anything to a `tag_name` element besides
+    // processing its children (a transform applying, `<Each>` expanding, or
+    // its text being dedented).
+    pub(crate) fn has_transform(&self, tag_name: &str) -> bool {
+        self.transforms.contains_key(tag_name)
+            || self.find_prefix_transform(tag_name).is_some()
+            || self.default_transform.is_some()
+            || (tag_name == "Each" && self.data.is_some())
+            || self.dedent_tags.contains(tag_name)
+    }
+
+    fn find_prefix_transform<'a>(
+        &self,
+        tag_name: &'a str,
+    ) -> Option<(&PrefixTransform<Ctx>, &'a str)> {
+        let (prefix, local_name) = tag_name.split_once(':')?;
+        self.prefix_transforms
+            .get(prefix)
+            .map(|transform| (transform, local_name))
+    }
+}
+
+impl<Ctx> Processor<Ctx>
+where
+    Ctx: Default,
+{
+    /// Processes `tree` with a fresh (default) context.
+    pub fn process(&self, tree: Tree) -> Result<Tree, ProcessError> {
+        self.process_with(&mut Ctx::default(), tree)
+    }
+
+    /// Processes each root of a fragment (a sequence of sibling trees).
+    pub fn process_fragment(&self, trees: Vec<Tree>) -> Result<Vec<Tree>, ProcessError> {
+        self.process_fragment_with(&mut Ctx::default(), trees)
+    }
+
+    /// Processes each of `trees` as `process` would, sharing one fresh
+    /// (default) context between them.
+    pub fn process_all(&self, trees: Vec<Tree>) -> Result<Vec<Tree>, ProcessError> {
+        self.process_all_with(&mut Ctx::default(), trees)
+    }
+
+    /// Processes `tree` like `process`, additionally recording what happened
+    /// to each inner node in the order the nodes were processed. Each node
+    /// is reported once: the processed children that a transform keeps in
+    /// its output aren't processed (or reported) again.
+    pub fn trace(&self, tree: Tree) -> Result<(Tree, Vec<TraceEvent>), ProcessError> {
+        let mut events = vec![];
+        let tree = self.process_root(&mut Ctx::default(), tree, &mut Some(&mut events))?;
+        Ok((tree, events))
+    }
+}
+
+impl<Ctx> Clone for Processor<Ctx> {
+    fn clone(&self) -> Self {
+        Processor {
+            transforms: self.transforms.clone(),
+            prefix_transforms: self.prefix_transforms.clone(),
+            default_transform: self.default_transform.clone(),
+            schemas: self.schemas.clone(),
+            dedent_tags: self.dedent_tags.clone(),
+            terminal_tags: self.terminal_tags.clone(),
+            data: self.data.clone(),
+        }
+    }
+}
+
+// Identifies an element by the buffers it owns, which move along with it, so
+// that a child a transform keeps can be told apart from an equal one it builds.
+#[derive(PartialEq, Eq, Hash)]
+struct ElementId(*const u8, *const Tree, usize);
+
+impl ElementId {
+    fn of(tree: &Tree) -> Option<ElementId> {
+        match tree {
+            // An empty tag name has no buffer of its own
+            Tree::Inner {
+                tag_name, children, ..
+            } if !tag_name.is_empty() => Some(ElementId(
+                tag_name.as_ptr(),
+                children.as_ptr(),
+                children.len(),
+            )),
+            _ => None,
+        }
+    }
+}
+
+// Describes the cycle that `tag`'s transform completes, given the tags whose
+// transforms produced it.
+fn transform_cycle(chain: &[String], tag: &str) -> String {
+    match chain.iter().rposition(|name| name == tag) {
+        Some(start) => format!(
+            "transform cycle: {} -> {}",
+            chain[start..].join(" -> "),
+            tag
+        ),
+        None => format!(
+            "transforms nested more than {} deep (ending with {} -> {})",
+            MAX_TRANSFORM_DEPTH,
+            chain[chain.len() - 1],
+            tag
+        ),
+    }
+}
+
+// Replaces the `{var.field}` placeholders in `tree` with `row`'s fields.
+fn substitute(tree: Tree, var: &str, row: &HashMap<String, String>) -> Result<Tree, String> {
+    Ok(match tree {
+        Tree::Text { text, span } => Tree::Text {
+            text: substitute_text(&text, var, row)?,
+            span,
+        },
+        Tree::Inner {
+            tag_name,
+            attrs,
+            children,
+            span,
+        } => Tree::Inner {
+            tag_name,
+            attrs: attrs
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = match value {
+                        AttrValue::Text(text) => AttrValue::Text(substitute_text(&text, var, row)?),
+                        AttrValue::Tree(tree) => AttrValue::Tree(substitute(tree, var, row)?),
+                        AttrValue::Bare => AttrValue::Bare,
+                    };
+                    Ok((name, value))
+                })
+                .collect::<Result<Attrs, String>>()?,
+            children: children
+                .into_iter()
+                .map(|child| substitute(child, var, row))
+                .collect::<Result<Vec<Tree>, String>>()?,
+            span,
+        },
+        comment => comment,
+    })
+}
+
+fn substitute_text(text: &str, var: &str, row: &HashMap<String, String>) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(open) = rest.find('{') {
+        let close = match rest[open..].find('}') {
+            Some(close) => open + close,
+            None => break,
+        };
+
+        let field = rest[open + 1..close]
+            .strip_prefix(var)
+            .and_then(|field| field.strip_prefix('.'));
+        match field {
+            Some(field) => {
+                let value = row
+                    .get(field)
+                    .ok_or_else(|| format!("{} has no field '{}'", var, field))?;
+                out.push_str(&rest[..open]);
+                out.push_str(value);
             }
+            None => out.push_str(&rest[..=close]),
         }
+        rest = &rest[close + 1..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn dedent(text: &str) -> String {
+    let is_blank = |line: &str| line.trim().is_empty();
+
+    let mut indent: Option<&str> = None;
+    for line in text.lines().filter(|line| !is_blank(line)) {
+        let line_indent = &line[..line.len() - line.trim_start().len()];
+        indent = Some(match indent {
+            None => line_indent,
+            Some(indent) => {
+                let common = indent
+                    .char_indices()
+                    .zip(line_indent.chars())
+                    .find(|((_, a), b)| a != b)
+                    .map_or(indent.len().min(line_indent.len()), |((i, _), _)| i);
+                &indent[..common]
+            }
+        });
+    }
+    let indent = indent.unwrap_or("");
+
+    let mut out = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        if is_blank(line) {
+            if line.ends_with('\n') {
+                out.push('\n');
+            }
+        } else {
+            out.push_str(&line[indent.len()..]);
+        }
+    }
+    out
+}
+
+/// What `Processor::merge` does when both processors have something
+/// registered under the same name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnConflict {
+    /// Keep the other processor's registration.
+    Replace,
+    /// Don't merge anything, and fail.
+    Error,
+}
+
+/// The names registered with both processors passed to `Processor::merge`.
+#[derive(Debug)]
+pub struct MergeError {
+    pub conflicts: Vec<String>,
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "can't merge processors, as both register {}",
+            self.conflicts.join(", ")
+        )
+    }
+}
+
+impl Error for MergeError {}
+
+/// A transform failed. The span is that of the element the transform was
+/// applied to, if it came from the source.
+#[derive(Debug)]
+pub struct ProcessError {
+    pub tag: String,
+    pub span: Option<Span>,
+    pub message: String,
+    // The source the span is in, for display
+    source: Option<Arc<SourceFile>>,
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, r#"failed to transform "{}": {}"#, self.tag, self.message)?;
+
+        match self.span {
+            Some(span) => fmt_source(f, self.source.as_deref(), span),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ProcessError {
+    /// An error for a transform to return. The processor fills in the span
+    /// of the element being transformed.
+    pub fn new<S, T>(tag: S, message: T) -> Self
+    where
+        S: Into<String>,
+        T: Into<String>,
+    {
+        ProcessError {
+            tag: tag.into(),
+            span: None,
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    /// Attaches the source that the error's span is in, so that the error is
+    /// displayed along with the element that failed.
+    pub fn with_source(self, source: Arc<SourceFile>) -> Self {
+        ProcessError {
+            source: Some(source),
+            ..self
+        }
+    }
+}
+
+impl Error for ProcessError {}
+
+/// A step taken while processing a tree, as reported by `Processor::trace`.
+#[derive(Debug, PartialEq)]
+pub enum TraceEvent {
+    /// A transform replaced the `tag` element (which had `from_children`
+    /// processed children) with a single `to` element, or with text or a
+    /// fragment if `to` is `None`.
+    Transformed {
+        tag: String,
+        from_children: usize,
+        to: Option<String>,
+    },
+    /// No transform matched the `tag` element, so it was left as-is.
+    PassedThrough { tag: String },
+}
+
+impl<Ctx> Default for Processor<Ctx> {
+    fn default() -> Self {
+        Self::with_context()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, ParseOptions};
+    use crate::render::Renderer;
+    use crate::schema::Children;
+
+    fn element(tag_name: &str, children: Vec<Tree>) -> Tree {
+        Tree::Inner {
+            tag_name: String::from(tag_name),
+            attrs: vec![],
+            children,
+            span: None,
+        }
+    }
+
+    fn transform_svg(local_name: &str, attrs: Attrs, children: Vec<Tree>) -> Tree {
+        Tree::Inner {
+            tag_name: format!("shape-{}", local_name),
+            attrs,
+            children,
+            span: None,
+        }
+    }
+
+    fn transform_svg_rect(_attrs: Attrs, _children: Vec<Tree>) -> Tree {
+        element("rect", vec![])
+    }
+
+    #[test]
+    fn prefix_transform_receives_local_name() {
+        let mut proc = Processor::new();
+        proc.add_prefix_transform("svg", transform_svg);
+
+        let tree = element(
+            "Doc",
+            vec![element("svg:rect", vec![]), element("svg:circle", vec![])],
+        );
+
+        assert_eq!(
+            proc.process(tree).unwrap().to_string(),
+            "<Doc><shape-rect></shape-rect><shape-circle></shape-circle></Doc>"
+        );
+    }
+
+    #[test]
+    fn exact_transform_takes_precedence_over_prefix() {
+        let mut proc = Processor::new();
+        proc.add_prefix_transform("svg", transform_svg);
+        proc.add_transform("svg:rect", transform_svg_rect);
+
+        let tree = element(
+            "Doc",
+            vec![
+                element("svg:rect", vec![]),
+                element("svg:circle", vec![]),
+                element("math:circle", vec![]),
+            ],
+        );
+
+        assert_eq!(
+            proc.process(tree).unwrap().to_string(),
+            "<Doc><rect></rect><shape-circle></shape-circle><math:circle></math:circle></Doc>"
+        );
+    }
+
+    fn transform_section(_attrs: Attrs, children: Vec<Tree>) -> Tree {
+        element("section", children)
+    }
+
+    #[test]
+    fn transform_cycles_are_reported() {
+        let mut proc = Processor::new();
+        proc.add_transform("Doc", |_, children| element("Section", children));
+        proc.add_transform("Section", |_, children| element("Doc", children));
+
+        let error = proc.process(element("Doc", vec![])).unwrap_err();
+        assert_eq!(error.message, "transform cycle: Doc -> Section -> Doc");
+
+        let mut proc = Processor::new();
+        proc.add_transform("p", |attrs, children| Tree::element("p", attrs, children));
+
+        let error = proc.process(element("p", vec![])).unwrap_err();
+        assert_eq!(error.message, "transform cycle: p -> p");
+    }
+
+    #[test]
+    fn terminal_transform_output_is_not_reprocessed() {
+        let mut proc = Processor::new();
+        proc.add_terminal_transform("p", |attrs, children| {
+            Tree::element("p", attrs, vec![element("span", children)])
+        });
+        proc.add_terminal_transform("Section", |_, children| {
+            element("div", vec![element("Section", children)])
+        });
+
+        let tree = element("Section", vec![element("p", vec![Tree::text("a")])]);
+
+        assert_eq!(
+            proc.process(tree).unwrap().to_string(),
+            "<div><Section><p><span>a</span></p></Section></div>"
+        );
+
+        // Registering an ordinary transform for the tag replaces the
+        // terminal one, but the `<p>` it's given isn't transformed again
+        proc.add_transform("Section", transform_section);
+        proc.add_transform("Doc", |_, children| {
+            element("html", vec![element("body", children)])
+        });
+        let tree = element(
+            "Doc",
+            vec![element(
+                "Section",
+                vec![element("p", vec![Tree::text("a")])],
+            )],
+        );
+        assert_eq!(
+            proc.process(tree).unwrap().to_string(),
+            "<html><body><section><p><span>a</span></p></section></body></html>"
+        );
+    }
+
+    #[test]
+    fn new_elements_equal_to_kept_children_are_processed() {
+        let mut proc = Processor::new();
+        proc.add_terminal_transform("p", |attrs, children| {
+            Tree::element("p", attrs, vec![element("span", children)])
+        });
+        // Keeps its child, and adds a copy of it, built from scratch
+        proc.add_transform("Twice", |_, children| {
+            let copy = element("p", vec![element("span", vec![Tree::text("a")])]);
+            assert_eq!(children, vec![copy.clone()]);
+            element("div", children.into_iter().chain(Some(copy)).collect())
+        });
+
+        let tree = element("Twice", vec![element("p", vec![Tree::text("a")])]);
+
+        assert_eq!(
+            proc.process(tree).unwrap().to_string(),
+            "<div><p><span>a</span></p><p><span><span>a</span></span></p></div>"
+        );
+    }
+
+    #[test]
+    fn cloned_processor_shares_transforms() {
+        let mut proc = Processor::new();
+        proc.add_transform("Section", transform_section);
+
+        let cloned = proc.clone();
+        let tree = element("Section", vec![]);
+
+        assert_eq!(
+            cloned.process(tree).unwrap().to_string(),
+            "<section></section>"
+        );
+    }
+
+    #[test]
+    fn modifying_clone_leaves_original_unchanged() {
+        let mut proc = Processor::new();
+        proc.add_transform("Section", transform_section);
+
+        let mut cloned = proc.clone();
+        cloned.add_transform("svg:rect", transform_svg_rect);
+        cloned.add_transform("Section", |_, _| element("div", vec![]));
+
+        let tree = element("Section", vec![element("svg:rect", vec![])]);
+        assert_eq!(
+            proc.process(tree).unwrap().to_string(),
+            "<section><svg:rect></svg:rect></section>"
+        );
+
+        let tree = element("Section", vec![element("svg:rect", vec![])]);
+        assert_eq!(cloned.process(tree).unwrap().to_string(), "<div></div>");
+    }
+
+    #[test]
+    fn trace_records_events_in_processing_order() {
+        let mut proc = Processor::new();
+        proc.add_transform("Section", transform_section);
+        proc.add_prefix_transform("svg", transform_svg);
+
+        let tree = element(
+            "Doc",
+            vec![
+                element("Title", vec![Tree::text("My first doc")]),
+                element("Section", vec![element("svg:rect", vec![])]),
+            ],
+        );
+
+        let (tree, events) = proc.trace(tree).unwrap();
+        assert_eq!(
+            tree.to_string(),
+            "<Doc><Title>My first doc</Title><section><shape-rect></shape-rect></section></Doc>"
+        );
+
+        use TraceEvent::*;
+        assert_eq!(
+            events,
+            vec![
+                PassedThrough {
+                    tag: String::from("Title")
+                },
+                Transformed {
+                    tag: String::from("svg:rect"),
+                    from_children: 0,
+                    to: Some(String::from("shape-rect")),
+                },
+                PassedThrough {
+                    tag: String::from("shape-rect")
+                },
+                Transformed {
+                    tag: String::from("Section"),
+                    from_children: 1,
+                    to: Some(String::from("section")),
+                },
+                PassedThrough {
+                    tag: String::from("section")
+                },
+                PassedThrough {
+                    tag: String::from("Doc")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn process_fragment_with_multiple_roots() {
+        let mut proc = Processor::new();
+        proc.add_transform("Section", transform_section);
+        proc.add_prefix_transform("svg", transform_svg);
+
+        let fragment = vec![
+            element("Section", vec![Tree::text("one")]),
+            element("svg:rect", vec![]),
+        ];
+
+        let out = proc.process_fragment(fragment).unwrap();
+        assert_eq!(
+            Renderer::new().render_fragment(&out),
+            "<section>one</section><shape-rect></shape-rect>"
+        );
+    }
+
+    #[test]
+    fn process_all_keeps_one_result_per_tree() {
+        let mut proc = Processor::new();
+        proc.add_transform("Section", transform_section);
+        proc.add_context_transform("Empty", |_, _, _| Ok(vec![]));
+
+        let out = proc
+            .process_all(vec![
+                element("Section", vec![Tree::text("one")]),
+                element("Section", vec![Tree::text("two")]),
+            ])
+            .unwrap();
+        let rendered: Vec<String> = out.iter().map(Tree::to_string).collect();
+        assert_eq!(
+            rendered,
+            vec!["<section>one</section>", "<section>two</section>"]
+        );
+
+        assert!(proc.process_all(vec![element("Empty", vec![])]).is_err());
+    }
+
+    #[test]
+    fn failing_transform_reports_element_span() {
+        let src = "<Doc>\n  <Date value=\"yesterday\"></Date>\n</Doc>";
+        let result = parse(src, &ParseOptions::new());
+        let tree = Tree::from(result.tree);
+
+        let mut proc = Processor::new();
+        proc.add_try_transform("Date", |attrs, _| {
+            Err(format!("invalid date {:?}", attrs[0].1.as_text().unwrap()))
+        });
+
+        let source = Arc::new(SourceFile::new("<test>", src));
+        let error = proc.process(tree).unwrap_err().with_source(source);
+        assert_eq!(error.tag, "Date");
+        assert_eq!(error.span, Some(Span::new(8, 39)));
+        assert_eq!(error.message, r#"invalid date "yesterday""#);
+        assert!(error.to_string().starts_with(
+            "failed to transform \"Date\": invalid date \"yesterday\"\n  <Date value"
+        ));
+    }
+
+    #[test]
+    fn validate_against_schemas() {
+        let src = r#"<Doc>
+  <Title>My first doc</Title>
+  <CodeListing lang="racket">(map <Mono>f</Mono> <Mono>xs</Mono>)</CodeListing>
+</Doc>"#;
+        let tree = Tree::from(parse(src, &ParseOptions::new()).tree);
+
+        let mut proc = Processor::new();
+
+        let mut doc = ComponentSchema::new();
+        doc.require_attr("title");
+        doc.set_children(Children::Elements(vec![
+            String::from("Title"),
+            String::from("CodeListing"),
+        ]));
+        proc.add_schema("Doc", doc);
+
+        let mut listing = ComponentSchema::new();
+        listing.require_attr("lang");
+        listing.set_children(Children::TextOnly);
+        proc.add_schema("CodeListing", listing);
+
+        let errors = proc.validate(&tree);
+        let messages: Vec<&str> = errors.iter().map(|error| error.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Doc requires attribute 'title'",
+                "CodeListing must contain only text",
+            ]
+        );
+        assert_eq!(errors[0].span, Some(Span::new(0, src.len())));
+        assert_eq!(errors[1].tag, "CodeListing");
+    }
+
+    #[test]
+    fn validate_disallowed_child_and_unknown_attr() {
+        let mut proc = Processor::new();
+        let mut section = ComponentSchema::new();
+        section.allow_attr("ref");
+        section.set_children(Children::Elements(vec![String::from("Para")]));
+        proc.add_schema("Section", section);
+
+        let tree = Tree::Inner {
+            tag_name: String::from("Section"),
+            attrs: vec![(String::from("id"), "s1".into())],
+            children: vec![element("Para", vec![]), element("Table", vec![])],
+            span: None,
+        };
+
+        let messages: Vec<String> = proc
+            .validate(&tree)
+            .into_iter()
+            .map(|error| error.message)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "Section doesn't accept attribute 'id'",
+                "Section can't contain <Table>",
+            ]
+        );
+    }
+
+    #[derive(Default)]
+    struct Footnotes {
+        notes: Vec<String>,
+    }
+
+    #[test]
+    fn context_transforms_share_state_and_produce_fragments() {
+        let marker = String::from("*");
+
+        let mut proc = Processor::with_context();
+        proc.add_context_transform("Note", move |notes: &mut Footnotes, _, children| {
+            let text = match &children[..] {
+                [Tree::Text { text, .. }] => text.clone(),
+                _ => return Err(ProcessError::new("Note", "expected text")),
+            };
+            notes.notes.push(text);
+
+            let number = notes.notes.len().to_string();
+            Ok(vec![
+                Tree::text(marker.repeat(notes.notes.len())),
+                element("sup", vec![Tree::text(number)]),
+            ])
+        });
+
+        let tree = element(
+            "p",
+            vec![
+                element("Note", vec![Tree::text("one")]),
+                element("Note", vec![Tree::text("two")]),
+            ],
+        );
+        let mut notes = Footnotes::default();
+
+        let out = proc.process_with(&mut notes, tree).unwrap();
+        assert_eq!(out.to_string(), "<p>*<sup>1</sup>**<sup>2</sup></p>");
+        assert_eq!(notes.notes, vec!["one", "two"]);
+
+        let src = "<p><Note><b>x</b></Note></p>";
+        let tree = Tree::from(parse(src, &ParseOptions::new()).tree);
+
+        let error = proc.process(tree).unwrap_err();
+        assert_eq!(error.tag, "Note");
+        assert_eq!(error.message, "expected text");
+        assert_eq!(error.span, Some(Span::new(3, 24)));
+    }
+
+    #[test]
+    fn root_must_become_a_single_tree() {
+        let mut proc = Processor::new();
+        proc.add_context_transform("Doc", |_: &mut (), _, children| Ok(children));
+
+        let tree = element("Doc", vec![element("a", vec![]), element("b", vec![])]);
+        let error = proc.process(tree).unwrap_err();
+        assert_eq!(error.message, "expected a single root, but found 2 trees");
+    }
+
+    #[test]
+    fn dedent_code_listings() {
+        let src =
+            "<Doc>\n  <CodeListing #>\n    (define (f x)\n      x)\n  </# CodeListing>\n</Doc>";
+        let tree = Tree::from(parse(src, &ParseOptions::new()).tree);
+
+        let mut proc = Processor::new();
+        proc.add_dedent("CodeListing");
+
+        assert_eq!(
+            proc.process(tree).unwrap().to_string(),
+            "<Doc>\n  <CodeListing>\n(define (f x)\n  x)\n</CodeListing>\n</Doc>"
+        );
+    }
+
+    #[test]
+    fn fenced_content_is_preserved_exactly() {
+        let code = "\n  main = do\n\tputStrLn  \"hi\"  \n\n    pure ()\n";
+        let src = format!(
+            "<Doc><Section><CodeListing.Haskell #>{}</# CodeListing.Haskell></Section></Doc>",
+            code
+        );
+        let tree = Tree::from(parse(&src, &ParseOptions::new()).tree);
+
+        let mut proc = Processor::new();
+        proc.add_transform("Section", transform_section);
+        let out = proc.process(tree).unwrap().normalize_whitespace();
+
+        match out.find_first("CodeListing.Haskell") {
+            Some(Tree::Inner { children, .. }) => {
+                assert_eq!(
+                    children,
+                    &vec![Tree::PreText {
+                        text: String::from(code),
+                        span: None,
+                    }]
+                )
+            }
+            tree => panic!("unexpected tree: {:?}", tree),
+        }
+        assert_eq!(
+            out.to_string(),
+            format!(
+                "<Doc><section><CodeListing.Haskell>{}</CodeListing.Haskell></section></Doc>",
+                code
+            )
+        );
+        assert_eq!(
+            format!("{:#}", out),
+            format!(
+                "<Doc>\n  <section>\n    <CodeListing.Haskell>{}</CodeListing.Haskell>\n  </section>\n</Doc>",
+                code
+            )
+        );
+    }
+
+    #[test]
+    fn dedent_uses_common_whitespace_prefix() {
+        assert_eq!(dedent("\t  a\n\t b\n"), " a\nb\n");
+        assert_eq!(dedent("    a\n\n  \n    b"), "a\n\n\nb");
+        assert_eq!(dedent("a\n  b"), "a\n  b");
+    }
+
+    #[test]
+    fn each_repeats_content_per_row() {
+        let src = r#"<table><Each over="rows" as="row"><tr id="r-{row.id}"><td>{row.name}</td></tr></Each></table>"#;
+        let tree = Tree::from(parse(src, &ParseOptions::new()).tree);
+
+        let row = |id: &str, name: &str| {
+            vec![
+                (String::from("id"), String::from(id)),
+                (String::from("name"), String::from(name)),
+            ]
+            .into_iter()
+            .collect()
+        };
+        let mut data = Data::new();
+        data.insert(
+            String::from("rows"),
+            vec![row("1", "Ada"), row("2", "Grace")],
+        );
+
+        let mut proc = Processor::new();
+        proc.set_data(data);
+        proc.add_transform("td", |attrs, children| Tree::Inner {
+            tag_name: String::from("th"),
+            attrs,
+            children,
+            span: None,
+        });
+
+        assert_eq!(
+            proc.process(tree).unwrap().to_string(),
+            r#"<table><tr id="r-1"><th>Ada</th></tr><tr id="r-2"><th>Grace</th></tr></table>"#
+        );
+    }
+
+    #[test]
+    fn each_reports_unknown_fields() {
+        let mut data = Data::new();
+        data.insert(String::from("rows"), vec![HashMap::new()]);
+        let mut proc = Processor::new();
+        proc.set_data(data);
+
+        let tree = Tree::Inner {
+            tag_name: String::from("Each"),
+            attrs: vec![(String::from("over"), "rows".into())],
+            children: vec![Tree::text("{item.name}")],
+            span: None,
+        };
+        let tree = tree.wrap("ul", vec![]);
+
+        let error = proc.process(tree).unwrap_err();
+        assert_eq!(error.tag, "Each");
+        assert_eq!(error.message, "item has no field 'name'");
+    }
+
+    #[test]
+    fn default_transform_handles_unmatched_tags() {
+        use std::cell::RefCell;
+
+        let src = "<Doc><Aside><Note>n</Note></Aside><Title>t</Title></Doc>";
+        let tree = Tree::from(parse(src, &ParseOptions::new()).tree);
+
+        let unhandled = Rc::new(RefCell::new(vec![]));
+        let log = Rc::clone(&unhandled);
+        let mut proc = Processor::new();
+        proc.add_transform("Doc", |_, children| element("body", children));
+        proc.add_transform("Title", |_, children| element("h1", children));
+        proc.add_transform("Note", |_, children| element("Title", children));
+        proc.set_default_transform(move |tag_name, _, children| {
+            log.borrow_mut().push(String::from(tag_name));
+            Tree::Inner {
+                tag_name: String::from("div"),
+                attrs: vec![(String::from("class"), tag_name.into())],
+                children,
+                span: None,
+            }
+        });
+
+        assert_eq!(
+            proc.process(tree).unwrap().to_string(),
+            r#"<body><div class="Aside"><h1>n</h1></div><h1>t</h1></body>"#
+        );
+        assert_eq!(*unhandled.borrow(), vec![String::from("Aside")]);
+    }
+
+    #[test]
+    fn merge_with_conflicts() {
+        let library = || {
+            let mut proc = Processor::new();
+            proc.add_transform("Section", transform_section);
+            proc.add_transform("svg:rect", transform_svg_rect);
+            proc
+        };
+        let mut proc = Processor::new();
+        proc.add_transform("Section", |attrs, children| Tree::Inner {
+            tag_name: String::from("div"),
+            attrs,
+            children,
+            span: None,
+        });
+        proc.add_prefix_transform("svg", transform_svg);
+        let tree = || {
+            element(
+                "Doc",
+                vec![element("Section", vec![]), element("svg:rect", vec![])],
+            )
+        };
+
+        let error = proc
+            .clone()
+            .merge(library(), OnConflict::Error)
+            .unwrap_err();
+        assert_eq!(error.conflicts, vec!["Section"]);
+        assert_eq!(
+            error.to_string(),
+            "can't merge processors, as both register Section"
+        );
+
+        let mut replaced = proc.clone();
+        replaced.merge(library(), OnConflict::Replace).unwrap();
+        assert_eq!(
+            replaced.process(tree()).unwrap().to_string(),
+            "<Doc><section></section><rect></rect></Doc>"
+        );
+
+        assert_eq!(
+            proc.process(tree()).unwrap().to_string(),
+            "<Doc><div></div><shape-rect></shape-rect></Doc>"
+        );
+    }
+
+    #[test]
+    fn closures_can_capture_state() {
+        use std::cell::Cell;
+
+        let next_id = Cell::new(1);
+        let prefix = String::from("h");
+
+        let mut proc = Processor::new();
+        proc.add_transform("Heading", move |mut attrs, children| {
+            attrs.push((
+                String::from("id"),
+                format!("{}{}", prefix, next_id.get()).into(),
+            ));
+            next_id.set(next_id.get() + 1);
+            Tree::Inner {
+                tag_name: String::from("h2"),
+                attrs,
+                children,
+                span: None,
+            }
+        });
+
+        let tree = element(
+            "Doc",
+            vec![element("Heading", vec![]), element("Heading", vec![])],
+        );
+        assert_eq!(
+            proc.process(tree).unwrap().to_string(),
+            r#"<Doc><h2 id="h1"></h2><h2 id="h2"></h2></Doc>"#
+        );
+    }
+
+    #[test]
+    fn transform_for_several_tags_receives_tag_name() {
+        let mut proc = Processor::new();
+        proc.add_transform_for(vec!["H1", "H2", "H3"], |tag_name, attrs, children| {
+            Tree::Inner {
+                tag_name: tag_name.to_lowercase(),
+                attrs,
+                children,
+                span: None,
+            }
+        });
+
+        let tree = element(
+            "Doc",
+            vec![
+                element("H1", vec![]),
+                element("H3", vec![]),
+                element("H4", vec![]),
+            ],
+        );
+        assert_eq!(
+            proc.process(tree).unwrap().to_string(),
+            "<Doc><h1></h1><h3></h3><H4></H4></Doc>"
+        );
     }
 }
@@ -0,0 +1,545 @@
+use super::tree::{AttrValue, Attrs, Tree, POSITIONAL_ATTR};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt::{self, Write};
+
+/// Renders trees as markup. A default `Renderer` produces the same output as
+/// `Tree`'s `Display` impl; the options below adjust that output.
+pub struct Renderer {
+    token_attrs: HashSet<String>,
+    dedup_tokens: bool,
+    sort_tokens: bool,
+    ascii_only: bool,
+    sort_attrs: bool,
+    collapse_whitespace: bool,
+    lowercase_tags: bool,
+    self_close_voids: bool,
+    escape_html: bool,
+    indent: Option<usize>,
+}
+
+// HTML's void elements, which can't have content
+const VOID_TAGS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+// The characters escaped by `set_escape_html`
+const TEXT_ESCAPES: &[(char, &str)] = &[('&', "&amp;"), ('<', "&lt;"), ('>', "&gt;")];
+const ATTR_ESCAPES: &[(char, &str)] = &[('&', "&amp;"), ('"', "&quot;")];
+
+impl Renderer {
+    pub fn new() -> Self {
+        Renderer {
+            token_attrs: vec![String::from("class")].into_iter().collect(),
+            dedup_tokens: false,
+            sort_tokens: false,
+            ascii_only: false,
+            sort_attrs: false,
+            collapse_whitespace: false,
+            lowercase_tags: false,
+            self_close_voids: false,
+            escape_html: false,
+            indent: None,
+        }
+    }
+
+    /// A renderer whose output depends only on a tree's content, for
+    /// comparing output across versions: attributes are sorted by name,
+    /// runs of whitespace in text are collapsed to a single space, tag names
+    /// are lowercased, and empty void elements are self-closed.
+    pub fn canonical() -> Self {
+        let mut renderer = Renderer::new();
+        renderer.set_sort_attrs(true);
+        renderer.set_collapse_whitespace(true);
+        renderer.set_lowercase_tags(true);
+        renderer.set_self_close_voids(true);
+        renderer
+    }
+
+    /// Sets the attributes whose values are treated as space-separated token
+    /// lists (`class` by default) by `set_dedup_tokens` and `set_sort_tokens`.
+    pub fn set_token_attrs<I, S>(&mut self, names: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.token_attrs = names.into_iter().map(|name| name.into()).collect();
+    }
+
+    /// Removes repeated tokens from token-list attributes, keeping the first
+    /// occurrence of each.
+    pub fn set_dedup_tokens(&mut self, dedup: bool) {
+        self.dedup_tokens = dedup;
+    }
+
+    /// Sorts the tokens of token-list attributes.
+    pub fn set_sort_tokens(&mut self, sort: bool) {
+        self.sort_tokens = sort;
+    }
+
+    /// Writes non-ASCII characters in text and attribute values as numeric
+    /// character references (like `&#233;` for `é`).
+    pub fn set_ascii_only(&mut self, ascii_only: bool) {
+        self.ascii_only = ascii_only;
+    }
+
+    /// Writes attributes sorted by name, rather than in their original order.
+    pub fn set_sort_attrs(&mut self, sort: bool) {
+        self.sort_attrs = sort;
+    }
+
+    /// Replaces each run of whitespace in text with a single space.
+    pub fn set_collapse_whitespace(&mut self, collapse: bool) {
+        self.collapse_whitespace = collapse;
+    }
+
+    pub fn set_lowercase_tags(&mut self, lowercase: bool) {
+        self.lowercase_tags = lowercase;
+    }
+
+    /// Writes void elements without content (like `br`) as a single
+    /// self-closing tag: `<br/>`.
+    pub fn set_self_close_voids(&mut self, self_close: bool) {
+        self.self_close_voids = self_close;
+    }
+
+    /// Escapes `<`, `>`, and `&` in text, and `&` and `"` in attribute
+    /// values, so that text containing them survives being written out.
+    /// Text is written verbatim by default, so that transforms can produce
+    /// raw HTML.
+    pub fn set_escape_html(&mut self, escape: bool) {
+        self.escape_html = escape;
+    }
+
+    /// Writes each element on its own line, with its children indented by
+    /// `indent` spaces more than it is. Elements containing only text
+    /// (without line breaks) are kept on one line, as are elements
+    /// containing preformatted text, so that it isn't reflowed. Other text is
+    /// trimmed, and dropped if it's only whitespace.
+    pub fn set_indent(&mut self, indent: Option<usize>) {
+        self.indent = indent;
+    }
+
+    pub fn render(&self, tree: &Tree) -> String {
+        let mut out = String::new();
+        self.write(&mut out, tree)
+            .expect("writing to a String can't fail");
+        out
+    }
+
+    /// Renders a fragment (a sequence of sibling trees) by concatenating the
+    /// rendered trees.
+    pub fn render_fragment(&self, trees: &[Tree]) -> String {
+        let mut out = String::new();
+        for tree in trees {
+            self.write(&mut out, tree)
+                .expect("writing to a String can't fail");
+        }
+        out
+    }
+
+    pub fn write<W>(&self, w: &mut W, tree: &Tree) -> fmt::Result
+    where
+        W: Write,
+    {
+        match self.indent {
+            Some(indent) => self.write_indented(w, tree, indent, 0),
+            None => self.write_inline(w, tree),
+        }
+    }
+
+    // Writes `tree` without recursing, so that trees of any depth can be
+    // written: each element's close tag is stacked beneath its children.
+    fn write_inline<W>(&self, w: &mut W, tree: &Tree) -> fmt::Result
+    where
+        W: Write,
+    {
+        use Tree::*;
+
+        let mut stack = vec![Step::Write(tree)];
+        while let Some(step) = stack.pop() {
+            let tree = match step {
+                Step::Write(tree) => tree,
+                Step::Close(tag_name) => {
+                    self.write_close_tag(w, tag_name)?;
+                    continue;
+                }
+            };
+
+            match tree {
+                Text { text, .. } => self.write_text(w, text)?,
+                PreText { text, .. } => self.write_pre_text(w, text)?,
+                Comment { text, .. } => self.write_comment(w, text)?,
+                Inner {
+                    tag_name,
+                    attrs,
+                    children,
+                    ..
+                } => {
+                    if self.self_close_voids && children.is_empty() && self.is_void(tag_name) {
+                        self.write_start_tag(w, tag_name, attrs)?;
+                        write!(w, "/>")?;
+                        continue;
+                    }
+
+                    self.write_open_tag(w, tag_name, attrs)?;
+                    stack.push(Step::Close(tag_name));
+                    stack.extend(children.iter().rev().map(Step::Write));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Writes `tree` starting at the current position, which is `depth`
+    // levels of indentation into a line.
+    fn write_indented<W>(&self, w: &mut W, tree: &Tree, indent: usize, depth: usize) -> fmt::Result
+    where
+        W: Write,
+    {
+        let (tag_name, attrs, children) = match tree {
+            Tree::Inner {
+                tag_name,
+                attrs,
+                children,
+                ..
+            } => (tag_name, attrs, children),
+            Tree::Text { text, .. } => return self.write_text(w, text.trim()),
+            tree => return self.write_inline(w, tree),
+        };
+
+        let keep_inline = children.iter().all(|child| match child {
+            Tree::Text { text, .. } => !text.contains('\n'),
+            _ => false,
+        }) || children
+            .iter()
+            .any(|child| matches!(child, Tree::PreText { .. }));
+        if keep_inline {
+            return self.write_inline(w, tree);
+        }
+
+        self.write_open_tag(w, tag_name, attrs)?;
+        for child in children {
+            if let Tree::Text { text, .. } = child {
+                if text.trim().is_empty() {
+                    continue;
+                }
+            }
+            write!(w, "\n{}", " ".repeat(indent * (depth + 1)))?;
+            self.write_indented(w, child, indent, depth + 1)?;
+        }
+        write!(w, "\n{}", " ".repeat(indent * depth))?;
+        self.write_close_tag(w, tag_name)
+    }
+
+    pub fn write_text<W>(&self, w: &mut W, text: &str) -> fmt::Result
+    where
+        W: Write,
+    {
+        if self.collapse_whitespace {
+            let mut collapsed = String::with_capacity(text.len());
+            let mut in_whitespace = false;
+            for c in text.chars() {
+                if c.is_whitespace() {
+                    if !in_whitespace {
+                        collapsed.push(' ');
+                    }
+                    in_whitespace = true;
+                } else {
+                    collapsed.push(c);
+                    in_whitespace = false;
+                }
+            }
+            write!(w, "{}", self.encode(&self.escape(&collapsed, TEXT_ESCAPES)))
+        } else {
+            write!(w, "{}", self.encode(&self.escape(text, TEXT_ESCAPES)))
+        }
+    }
+
+    /// Writes preformatted text, which (unlike other text) never has its
+    /// whitespace collapsed.
+    pub fn write_pre_text<W>(&self, w: &mut W, text: &str) -> fmt::Result
+    where
+        W: Write,
+    {
+        write!(w, "{}", self.encode(&self.escape(text, TEXT_ESCAPES)))
+    }
+
+    pub fn write_comment<W>(&self, w: &mut W, text: &str) -> fmt::Result
+    where
+        W: Write,
+    {
+        write!(w, "<!--{}-->", self.encode(text))
+    }
+
+    pub fn write_open_tag<W>(&self, w: &mut W, tag_name: &str, attrs: &Attrs) -> fmt::Result
+    where
+        W: Write,
+    {
+        self.write_start_tag(w, tag_name, attrs)?;
+        write!(w, ">")
+    }
+
+    // Writes an open tag, without its '>'.
+    fn write_start_tag<W>(&self, w: &mut W, tag_name: &str, attrs: &Attrs) -> fmt::Result
+    where
+        W: Write,
+    {
+        write!(w, "<{}", self.tag_name(tag_name))?;
+
+        let mut attrs: Vec<_> = attrs.iter().collect();
+        if self.sort_attrs {
+            attrs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        }
+
+        for (name, value) in attrs {
+            match value {
+                AttrValue::Text(value) => {
+                    let value = self.attr_value(name, value);
+                    let value = self.escape(&value, ATTR_ESCAPES);
+                    if name == POSITIONAL_ATTR {
+                        write!(w, " \"{}\"", self.encode(&value))?;
+                    } else {
+                        write!(w, " {}=\"{}\"", name, self.encode(&value))?;
+                    }
+                }
+                AttrValue::Tree(tree) => {
+                    // Element values are written in the syntax they're
+                    // parsed from
+                    write!(w, " {}=", name)?;
+                    self.write(w, tree)?;
+                }
+                AttrValue::Bare => write!(w, " {}", name)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_close_tag<W>(&self, w: &mut W, tag_name: &str) -> fmt::Result
+    where
+        W: Write,
+    {
+        write!(w, "</{}>", self.tag_name(tag_name))
+    }
+
+    fn tag_name<'a>(&self, tag_name: &'a str) -> Cow<'a, str> {
+        if self.lowercase_tags {
+            Cow::Owned(tag_name.to_lowercase())
+        } else {
+            Cow::Borrowed(tag_name)
+        }
+    }
+
+    fn is_void(&self, tag_name: &str) -> bool {
+        VOID_TAGS.contains(&self.tag_name(tag_name).as_ref())
+    }
+
+    fn escape<'a>(&self, text: &'a str, escapes: &[(char, &str)]) -> Cow<'a, str> {
+        if !self.escape_html || !text.contains(|c| escapes.iter().any(|&(e, _)| e == c)) {
+            return Cow::Borrowed(text);
+        }
+
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            match escapes.iter().find(|&&(e, _)| e == c) {
+                Some((_, entity)) => out.push_str(entity),
+                None => out.push(c),
+            }
+        }
+        Cow::Owned(out)
+    }
+
+    fn encode(&self, text: &str) -> String {
+        if !self.ascii_only {
+            return String::from(text);
+        }
+
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            if c.is_ascii() {
+                out.push(c);
+            } else {
+                out.push_str(&format!("&#{};", c as u32));
+            }
+        }
+        out
+    }
+
+    fn attr_value(&self, name: &str, value: &str) -> String {
+        if !(self.dedup_tokens || self.sort_tokens) || !self.token_attrs.contains(name) {
+            return String::from(value);
+        }
+
+        let mut tokens: Vec<&str> = vec![];
+        for token in value.split_whitespace() {
+            if !self.dedup_tokens || !tokens.contains(&token) {
+                tokens.push(token);
+            }
+        }
+
+        if self.sort_tokens {
+            tokens.sort_unstable();
+        }
+
+        tokens.join(" ")
+    }
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// What's left to do in `Renderer::write_inline`.
+enum Step<'a> {
+    Write(&'a Tree),
+    Close(&'a str),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(class: &str) -> Tree {
+        Tree::Inner {
+            tag_name: String::from("div"),
+            attrs: vec![(String::from("class"), class.into())],
+            children: vec![],
+            span: None,
+        }
+    }
+
+    #[test]
+    fn default_renderer_leaves_tokens_alone() {
+        let tree = element("box red box");
+        assert_eq!(
+            Renderer::new().render(&tree),
+            r#"<div class="box red box"></div>"#
+        );
+    }
+
+    #[test]
+    fn dedup_tokens() {
+        let mut renderer = Renderer::new();
+        renderer.set_dedup_tokens(true);
+
+        let tree = element("box red box");
+        assert_eq!(renderer.render(&tree), r#"<div class="box red"></div>"#);
+    }
+
+    #[test]
+    fn dedup_and_sort_tokens() {
+        let mut renderer = Renderer::new();
+        renderer.set_dedup_tokens(true);
+        renderer.set_sort_tokens(true);
+
+        let tree = element("red  box red");
+        assert_eq!(renderer.render(&tree), r#"<div class="box red"></div>"#);
+    }
+
+    #[test]
+    fn only_token_attrs_are_normalized() {
+        let mut renderer = Renderer::new();
+        renderer.set_dedup_tokens(true);
+        renderer.set_token_attrs(vec!["rel"]);
+
+        let tree = element("x x");
+        assert_eq!(renderer.render(&tree), r#"<div class="x x"></div>"#);
+
+        let tree = Tree::Inner {
+            tag_name: String::from("a"),
+            attrs: vec![(String::from("rel"), "nofollow nofollow".into())],
+            children: vec![],
+            span: None,
+        };
+        assert_eq!(renderer.render(&tree), r#"<a rel="nofollow"></a>"#);
+    }
+
+    #[test]
+    fn ascii_only_encodes_non_ascii() {
+        let mut renderer = Renderer::new();
+        renderer.set_ascii_only(true);
+
+        let tree = Tree::Inner {
+            tag_name: String::from("p"),
+            attrs: vec![(String::from("title"), "naïve".into())],
+            children: vec![Tree::text("café & 😀")],
+            span: None,
+        };
+        assert_eq!(
+            renderer.render(&tree),
+            r#"<p title="na&#239;ve">caf&#233; & &#128512;</p>"#
+        );
+    }
+
+    #[test]
+    fn attributes_are_separated_by_single_spaces() {
+        let tree = Tree::Inner {
+            tag_name: String::from("CodeListing"),
+            attrs: vec![
+                (String::from("lang"), "racket".into()),
+                (String::from("title"), "Conditionals".into()),
+                (String::from("class"), "wide".into()),
+            ],
+            children: vec![],
+            span: None,
+        };
+        assert_eq!(
+            Renderer::new().render(&tree),
+            r#"<CodeListing lang="racket" title="Conditionals" class="wide"></CodeListing>"#
+        );
+    }
+
+    #[test]
+    fn canonical_rendering_ignores_attribute_order() {
+        let image = |attrs: Vec<(&str, &str)>| Tree::Inner {
+            tag_name: String::from("Figure"),
+            attrs: vec![],
+            children: vec![
+                Tree::text("\n  A  caption\n"),
+                Tree::Inner {
+                    tag_name: String::from("IMG"),
+                    attrs: attrs
+                        .into_iter()
+                        .map(|(name, value)| (String::from(name), value.into()))
+                        .collect(),
+                    children: vec![],
+                    span: None,
+                },
+            ],
+            span: None,
+        };
+        let a = image(vec![("src", "a.png"), ("alt", "A")]);
+        let b = image(vec![("alt", "A"), ("src", "a.png")]);
+
+        let renderer = Renderer::canonical();
+        assert_eq!(renderer.render(&a), renderer.render(&b));
+        assert_eq!(
+            renderer.render(&a),
+            r#"<figure> A caption <img alt="A" src="a.png"/></figure>"#
+        );
+    }
+
+    #[test]
+    fn escape_html() {
+        let tree = Tree::Inner {
+            tag_name: String::from("p"),
+            attrs: vec![(String::from("title"), r#"Tom & "Jerry" <3"#.into())],
+            children: vec![Tree::text("1 < 2 && 3 > 2 \"ok\"")],
+            span: None,
+        };
+        assert_eq!(
+            Renderer::new().render(&tree),
+            r#"<p title="Tom & "Jerry" <3">1 < 2 && 3 > 2 "ok"</p>"#
+        );
+
+        let mut renderer = Renderer::new();
+        renderer.set_escape_html(true);
+        assert_eq!(
+            renderer.render(&tree),
+            r#"<p title="Tom &amp; &quot;Jerry&quot; <3">1 &lt; 2 &amp;&amp; 3 &gt; 2 "ok"</p>"#
+        );
+    }
+}
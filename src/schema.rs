@@ -0,0 +1,161 @@
+use super::common::Span;
+use super::file::SourceFile;
+use super::syntax_error::fmt_source;
+use super::tree::Tree;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+/// Declares the attributes and children that a component (an element with a
+/// particular tag name) may have. Schemas are registered with
+/// `Processor::add_schema` and checked by `Processor::validate`.
+///
+/// Every attribute a component accepts must be declared, as either required
+/// or optional.
+#[derive(Clone, Debug, Default)]
+pub struct ComponentSchema {
+    required_attrs: Vec<String>,
+    optional_attrs: Vec<String>,
+    children: Children,
+}
+
+/// What a component may contain.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Children {
+    #[default]
+    Any,
+    /// Text, but no elements (an element containing any is reported once,
+    /// rather than once for each)
+    TextOnly,
+    /// Text and elements with the given tag names
+    Elements(Vec<String>),
+}
+
+impl ComponentSchema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn require_attr<S>(&mut self, name: S)
+    where
+        S: Into<String>,
+    {
+        self.required_attrs.push(name.into());
+    }
+
+    pub fn allow_attr<S>(&mut self, name: S)
+    where
+        S: Into<String>,
+    {
+        self.optional_attrs.push(name.into());
+    }
+
+    pub fn set_children(&mut self, children: Children) {
+        self.children = children;
+    }
+
+    // Checks `tree` (but not its descendants) against the schema.
+    pub(crate) fn check(&self, tree: &Tree, errors: &mut Vec<ValidationError>) {
+        let (tag_name, attrs, children, span) = match tree {
+            Tree::Inner {
+                tag_name,
+                attrs,
+                children,
+                span,
+            } => (tag_name, attrs, children, *span),
+            Tree::Text { .. } | Tree::PreText { .. } | Tree::Comment { .. } => return,
+        };
+        let mut report = |message: String| {
+            errors.push(ValidationError {
+                tag: tag_name.clone(),
+                span,
+                message,
+                source: None,
+            })
+        };
+
+        for required in &self.required_attrs {
+            if !attrs.iter().any(|(name, _)| name == required) {
+                report(format!("{} requires attribute '{}'", tag_name, required));
+            }
+        }
+
+        for (name, _) in attrs {
+            if !self.required_attrs.contains(name) && !self.optional_attrs.contains(name) {
+                report(format!("{} doesn't accept attribute '{}'", tag_name, name));
+            }
+        }
+
+        for child in children {
+            let child_tag = match child {
+                Tree::Inner { tag_name, .. } => tag_name,
+                Tree::Text { .. } | Tree::PreText { .. } | Tree::Comment { .. } => continue,
+            };
+
+            match &self.children {
+                Children::Any => {}
+                // Reported once, at the element, however many elements
+                // it contains
+                Children::TextOnly => {
+                    report(format!("{} must contain only text", tag_name));
+                    break;
+                }
+                Children::Elements(allowed) => {
+                    if !allowed.contains(child_tag) {
+                        report(format!("{} can't contain <{}>", tag_name, child_tag));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// An element that doesn't match its component's schema.
+#[derive(Debug)]
+pub struct ValidationError {
+    pub tag: String,
+    pub span: Option<Span>,
+    pub message: String,
+    // The source the span is in, for display
+    source: Option<Arc<SourceFile>>,
+}
+
+impl ValidationError {
+    /// Attaches the source that the error's span is in, so that the error is
+    /// displayed along with the invalid element.
+    pub fn with_source(self, source: Arc<SourceFile>) -> Self {
+        ValidationError {
+            source: Some(source),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+
+        match self.span {
+            Some(span) => fmt_source(f, self.source.as_deref(), span),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+/// The validation errors that stopped a document from being processed (as
+/// returned by `run`).
+#[derive(Debug)]
+pub struct ValidationErrors(pub Vec<ValidationError>);
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for error in &self.0 {
+            writeln!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ValidationErrors {}
@@ -0,0 +1,335 @@
+use super::lexer::NameChars;
+use super::tree::Tree;
+use std::error::Error;
+use std::fmt;
+
+// Selectors are a subset of CSS's:
+//
+//   selector   = compound (combinator compound)*
+//   combinator = whitespace          (descendant)
+//              | ws? ">" ws?         (child)
+//   compound   = name attr* | attr+
+//   name       = a tag name, or "*" for any
+//   attr       = "[" attr-name "]"                 (presence)
+//              | "[" attr-name "=" '"' value '"' "]"  (equality)
+//
+// Names are made of the characters allowed in tag and attribute names by
+// default (see `NameChars`), including `.` and `:`, so that
+// `CodeListing.Racket` selects that tag rather than a class. Names that are
+// only allowed by custom `NameChars` (like `@click`) can't be selected.
+
+impl Tree {
+    /// Returns the elements in this tree (including the tree itself) matched
+    /// by `selector`, in document order. For example, `Doc > Section
+    /// CodeListing[lang="racket"]` selects the `CodeListing` elements with
+    /// that `lang` anywhere inside a `Section` that is a child of a `Doc`.
+    /// See `select.rs` for the full grammar.
+    ///
+    /// # Panics
+    ///
+    /// If `selector` isn't valid. Use `try_select` for selectors that aren't
+    /// known in advance.
+    pub fn select(&self, selector: &str) -> Vec<&Tree> {
+        self.try_select(selector)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Like `select`, but fails if `selector` isn't valid.
+    pub fn try_select(&self, selector: &str) -> Result<Vec<&Tree>, SelectorError> {
+        let parsed = Selector::parse(selector).map_err(|message| SelectorError {
+            selector: String::from(selector),
+            message,
+        })?;
+
+        let mut selected = vec![];
+        select_into(self, &parsed, &mut vec![], &mut selected);
+        Ok(selected)
+    }
+}
+
+/// A selector passed to `Tree::try_select` isn't valid.
+#[derive(Debug)]
+pub struct SelectorError {
+    pub selector: String,
+    pub message: String,
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid selector {:?}: {}", self.selector, self.message)
+    }
+}
+
+impl Error for SelectorError {}
+
+fn select_into<'a>(
+    tree: &'a Tree,
+    selector: &Selector,
+    ancestors: &mut Vec<&'a Tree>,
+    selected: &mut Vec<&'a Tree>,
+) {
+    if let Tree::Inner { children, .. } = tree {
+        if selector.matches(tree, ancestors) {
+            selected.push(tree);
+        }
+
+        ancestors.push(tree);
+        for child in children {
+            select_into(child, selector, ancestors, selected);
+        }
+        ancestors.pop();
+    }
+}
+
+struct Selector {
+    // The first compound, followed by each combinator and the compound
+    // after it
+    first: Compound,
+    rest: Vec<(Combinator, Compound)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Combinator {
+    Descendant,
+    Child,
+}
+
+struct Compound {
+    // `None` matches any tag
+    tag_name: Option<String>,
+    attrs: Vec<(String, Option<String>)>,
+}
+
+impl Selector {
+    fn parse(input: &str) -> Result<Self, String> {
+        let mut parser = SelectorParser { rest: input.trim() };
+
+        let first = parser.compound()?;
+        let mut rest = vec![];
+        while !parser.rest.is_empty() {
+            let combinator = parser.combinator()?;
+            rest.push((combinator, parser.compound()?));
+        }
+
+        Ok(Selector { first, rest })
+    }
+
+    fn matches(&self, tree: &Tree, ancestors: &[&Tree]) -> bool {
+        let mut compounds = vec![(None, &self.first)];
+        compounds.extend(
+            self.rest
+                .iter()
+                .map(|(combinator, compound)| (Some(*combinator), compound)),
+        );
+
+        matches_from(&compounds, tree, ancestors)
+    }
+}
+
+// Whether `tree` matches the last compound, and its ancestors match the
+// preceding ones (as related by the combinators).
+fn matches_from(
+    compounds: &[(Option<Combinator>, &Compound)],
+    tree: &Tree,
+    ancestors: &[&Tree],
+) -> bool {
+    let ((combinator, compound), preceding) = match compounds.split_last() {
+        Some(last) => last,
+        None => return true,
+    };
+    if !compound.matches(tree) {
+        return false;
+    }
+
+    match combinator {
+        None => true,
+        Some(Combinator::Child) => match ancestors.split_last() {
+            Some((parent, ancestors)) => matches_from(preceding, parent, ancestors),
+            None => false,
+        },
+        Some(Combinator::Descendant) => (0..ancestors.len())
+            .rev()
+            .any(|i| matches_from(preceding, ancestors[i], &ancestors[..i])),
+    }
+}
+
+impl Compound {
+    fn matches(&self, tree: &Tree) -> bool {
+        let (tag_name, attrs) = match tree {
+            Tree::Inner {
+                tag_name, attrs, ..
+            } => (tag_name, attrs),
+            _ => return false,
+        };
+
+        if let Some(name) = &self.tag_name {
+            if name != tag_name {
+                return false;
+            }
+        }
+
+        self.attrs.iter().all(|(name, value)| {
+            attrs.iter().any(|(attr_name, attr_value)| {
+                attr_name == name
+                    && match value {
+                        Some(value) => attr_value.as_text() == Some(value),
+                        None => true,
+                    }
+            })
+        })
+    }
+}
+
+struct SelectorParser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> SelectorParser<'a> {
+    fn combinator(&mut self) -> Result<Combinator, String> {
+        let trimmed = self.rest.trim_start();
+        let had_whitespace = trimmed.len() < self.rest.len();
+
+        if let Some(rest) = trimmed.strip_prefix('>') {
+            self.rest = rest.trim_start();
+            Ok(Combinator::Child)
+        } else if had_whitespace {
+            self.rest = trimmed;
+            Ok(Combinator::Descendant)
+        } else {
+            Err(format!("expected a combinator before {:?}", self.rest))
+        }
+    }
+
+    fn compound(&mut self) -> Result<Compound, String> {
+        let any = self.rest.starts_with('*');
+        let tag_name = if any {
+            self.rest = &self.rest[1..];
+            None
+        } else {
+            self.name()
+        };
+
+        let mut attrs = vec![];
+        while let Some(rest) = self.rest.strip_prefix('[') {
+            self.rest = rest;
+            let name = self.name().ok_or("expected an attribute name")?;
+
+            let value = match self.rest.strip_prefix("=\"") {
+                Some(rest) => {
+                    let end = rest.find('"').ok_or("unterminated attribute value")?;
+                    self.rest = &rest[end + 1..];
+                    Some(String::from(&rest[..end]))
+                }
+                None => None,
+            };
+
+            self.rest = self.rest.strip_prefix(']').ok_or("expected ']'")?;
+            attrs.push((name, value));
+        }
+
+        if !any && tag_name.is_none() && attrs.is_empty() {
+            return Err(format!(
+                "expected a tag name, '*', or '[' at {:?}",
+                self.rest
+            ));
+        }
+
+        Ok(Compound { tag_name, attrs })
+    }
+
+    fn name(&mut self) -> Option<String> {
+        let name_chars = NameChars::default();
+        if !self.rest.starts_with(name_chars.start) {
+            return None;
+        }
+        let end = self
+            .rest
+            .find(|c| !(name_chars.rest)(c))
+            .unwrap_or(self.rest.len());
+
+        let name = &self.rest[..end];
+        self.rest = &self.rest[end..];
+        Some(String::from(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, ParseOptions};
+
+    const DOC: &str = r#"<Doc>
+  <Title>My first doc</Title>
+  <Subtitle>Using <Mono>map</Mono> and <Em><Mono>filter</Mono></Em></Subtitle>
+  <Section ref="sec-1">
+    <Mono>xs</Mono>
+    <CodeListing.Racket lang="racket"></CodeListing.Racket>
+  </Section>
+</Doc>"#;
+
+    fn doc() -> Tree {
+        Tree::from(parse(DOC, &ParseOptions::new()).tree)
+    }
+
+    fn texts(selected: Vec<&Tree>) -> Vec<String> {
+        selected
+            .into_iter()
+            .map(|tree| match tree {
+                Tree::Inner { children, .. } => children.iter().map(|c| c.to_string()).collect(),
+                _ => panic!("expected inner node"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn descendant_and_child_combinators() {
+        let doc = doc();
+
+        assert_eq!(
+            texts(doc.select("Doc > Subtitle Mono")),
+            vec!["map", "filter"]
+        );
+        assert_eq!(texts(doc.select("Subtitle > Mono")), vec!["map"]);
+        assert_eq!(texts(doc.select("Doc Mono")).len(), 3);
+        assert!(doc.select("Section > Subtitle").is_empty());
+        assert_eq!(doc.select("*").len(), 9);
+    }
+
+    #[test]
+    fn attribute_selectors() {
+        let doc = doc();
+
+        assert_eq!(doc.select("[ref]").len(), 1);
+        assert_eq!(
+            doc.select(r#"Section[ref="sec-1"] > *[lang="racket"]"#)
+                .len(),
+            1
+        );
+        assert!(doc.select(r#"[ref="sec-2"]"#).is_empty());
+        assert_eq!(doc.select("CodeListing.Racket").len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid selector")]
+    fn invalid_selector() {
+        doc().select("Doc >");
+    }
+
+    #[test]
+    fn try_select_reports_invalid_selectors() {
+        let doc = doc();
+        assert_eq!(doc.try_select("Section > Mono").unwrap().len(), 1);
+
+        let error = doc.try_select("Doc >").unwrap_err();
+        assert_eq!(error.selector, "Doc >");
+        assert_eq!(
+            error.to_string(),
+            r#"invalid selector "Doc >": expected a tag name, '*', or '[' at """#
+        );
+
+        // Names follow the default rules for tag names, so can't start with
+        // a digit
+        assert!(doc.try_select("2col").is_err());
+        assert!(doc.try_select("[data-2]").is_ok());
+    }
+}
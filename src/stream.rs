@@ -0,0 +1,325 @@
+use super::common::Span;
+use super::parser::{self, ParseOptions, TreeKind, TreeSink};
+use super::processor::Processor;
+use super::render::Renderer;
+use super::syntax_error::{self, SyntaxError};
+use super::tree::{AttrValue, Attrs};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+
+/// Parses `input` (according to `options`) and writes the rendered document to `w` as each element is
+/// completed, without building a tree.
+///
+/// Since an element's output is written before its children have been seen,
+/// transforms (which need an element's children) can't be applied: streaming
+/// only supports documents in which every element is passed through. The
+/// first element that `proc` has a transform for (exactly or by prefix),
+/// that it would expand (an `<Each>`, once `Processor::set_data` has been
+/// called), or whose text it would dedent (see `Processor::add_dedent`)
+/// stops the output and is reported as `StreamError::Unstreamable`. For
+/// documents without such elements, the output is identical to rendering
+/// the processed tree.
+pub fn render_streaming<W>(
+    input: &str,
+    options: &ParseOptions,
+    proc: &Processor,
+    w: &mut W,
+) -> Result<(), StreamError>
+where
+    W: Write,
+{
+    let mut sink = StreamSink {
+        proc,
+        renderer: Renderer::new(),
+        options,
+        out: w,
+        frames: vec![],
+        errors: vec![],
+        failure: None,
+    };
+
+    parser::parse_into(input, &mut sink);
+
+    match sink.failure {
+        Some(failure) => Err(failure),
+        None if sink.errors.iter().any(SyntaxError::is_error) => Err(StreamError::Syntax(
+            syntax_error::with_input_source(sink.errors, input),
+        )),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug)]
+pub enum StreamError {
+    Io(io::Error),
+    Syntax(Vec<SyntaxError>),
+    Unstreamable { tag: String },
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamError::Io(error) => write!(f, "{}", error),
+            StreamError::Syntax(errors) => {
+                for error in errors {
+                    writeln!(f, "{}", error)?;
+                }
+                Ok(())
+            }
+            StreamError::Unstreamable { tag } => write!(
+                f,
+                r#"the transform for "{}" can't be applied while streaming"#,
+                tag
+            ),
+        }
+    }
+}
+
+impl Error for StreamError {}
+
+struct StreamSink<'a, W> {
+    proc: &'a Processor,
+    renderer: Renderer,
+    options: &'a ParseOptions,
+    out: &'a mut W,
+    // The nodes currently open, innermost last
+    frames: Vec<Frame>,
+    errors: Vec<SyntaxError>,
+    // Once set, nothing more is written
+    failure: Option<StreamError>,
+}
+
+enum Frame {
+    Document,
+    // The tag name is recorded once the element's open tag is complete
+    InnerNode(Option<String>),
+    OpenTag(Option<String>, Attrs),
+    Attrs,
+    Attr(Option<String>, Option<String>),
+    CloseTag,
+    Other,
+}
+
+impl<'a, W> StreamSink<'a, W>
+where
+    W: Write,
+{
+    fn emit<F>(&mut self, render: F)
+    where
+        F: FnOnce(&Renderer, &mut String) -> fmt::Result,
+    {
+        if self.failure.is_some() {
+            return;
+        }
+
+        let mut chunk = String::new();
+        render(&self.renderer, &mut chunk).expect("writing to a String can't fail");
+        if let Err(error) = self.out.write_all(chunk.as_bytes()) {
+            self.failure = Some(StreamError::Io(error));
+        }
+    }
+
+    fn complete_open_tag(&mut self, name: Option<String>, attrs: Attrs) {
+        let name = match name {
+            Some(name) => name,
+            None => return,
+        };
+
+        if self.proc.has_transform(&name) {
+            if self.failure.is_none() {
+                self.failure = Some(StreamError::Unstreamable { tag: name });
+            }
+            return;
+        }
+
+        self.emit(|renderer, chunk| renderer.write_open_tag(chunk, &name, &attrs));
+        if let Some(Frame::InnerNode(tag_name)) = self.frames.last_mut() {
+            *tag_name = Some(name);
+        }
+    }
+}
+
+impl<'a, W> TreeSink for StreamSink<'a, W>
+where
+    W: Write,
+{
+    fn open(&mut self, kind: TreeKind, _start: usize) {
+        let frame = match kind {
+            TreeKind::Document => Frame::Document,
+            TreeKind::InnerNode => Frame::InnerNode(None),
+            TreeKind::OpenTag => Frame::OpenTag(None, vec![]),
+            TreeKind::Attrs => Frame::Attrs,
+            TreeKind::Attr => Frame::Attr(None, None),
+            TreeKind::CloseTag => Frame::CloseTag,
+            _ => Frame::Other,
+        };
+        self.frames.push(frame);
+    }
+
+    fn add_leaf(&mut self, kind: TreeKind, _span: Span) {
+        match (self.frames.last_mut(), kind) {
+            (Some(Frame::OpenTag(name, _)), TreeKind::TagName(tag_name)) => {
+                *name = Some(tag_name);
+            }
+            (Some(Frame::Attr(name, _)), TreeKind::AttrName(attr_name)) => {
+                *name = Some(attr_name);
+            }
+            (Some(Frame::Attr(_, value)), TreeKind::AttrVal(attr_val)) => {
+                *value = Some(attr_val);
+            }
+            (Some(Frame::InnerNode(_)), TreeKind::TextNode(text)) => {
+                self.emit(|renderer, chunk| renderer.write_text(chunk, &text));
+            }
+            (Some(Frame::InnerNode(_)), TreeKind::RawTextNode(text)) => {
+                self.emit(|renderer, chunk| renderer.write_pre_text(chunk, &text));
+            }
+            (Some(Frame::InnerNode(_)), TreeKind::Comment(text)) => {
+                self.emit(|renderer, chunk| renderer.write_comment(chunk, &text));
+            }
+            _ => {}
+        }
+    }
+
+    fn complete_with(&mut self, _end: usize, _recovered: bool) {
+        match self.frames.pop() {
+            Some(Frame::InnerNode(Some(tag_name))) => {
+                self.emit(|renderer, chunk| renderer.write_close_tag(chunk, &tag_name));
+            }
+            Some(Frame::OpenTag(name, attrs)) => self.complete_open_tag(name, attrs),
+            Some(Frame::Attr(Some(name), value)) => {
+                // Attributes are collected by the enclosing open tag
+                let open_tag = self.frames.iter_mut().rev().find_map(|frame| match frame {
+                    Frame::OpenTag(_, attrs) => Some(attrs),
+                    _ => None,
+                });
+                if let Some(attrs) = open_tag {
+                    attrs.push((name, value.map_or(AttrValue::Bare, AttrValue::Text)));
+                }
+            }
+            Some(_) => {}
+            None => panic!("no open item to complete"),
+        }
+    }
+
+    fn add_error(&mut self, error: SyntaxError) {
+        self.errors.push(error);
+    }
+
+    fn options(&self) -> &ParseOptions {
+        self.options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::UnknownCharPolicy;
+    use crate::processor::Data;
+    use crate::tree::Tree;
+
+    const DOC: &str = r#"<Doc>
+  <Title lang="en">My first doc</Title>
+
+  <Section ref="sec-1">
+    <CodeListing.Racket #>
+(if (< x 1) 'small 'large)
+    </# CodeListing.Racket>
+  </Section>
+</Doc>"#;
+
+    fn render_buffered(input: &str, proc: &Processor) -> String {
+        let result = parser::parse(input, &ParseOptions::new());
+        assert!(result.errors.is_empty());
+        proc.process(Tree::from(result.tree)).unwrap().to_string()
+    }
+
+    #[test]
+    fn streaming_matches_buffered_output() {
+        let proc = Processor::new();
+        let mut out = vec![];
+
+        render_streaming(DOC, &ParseOptions::new(), &proc, &mut out).unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), render_buffered(DOC, &proc));
+    }
+
+    #[test]
+    fn transformed_elements_are_unstreamable() {
+        let mut proc = Processor::new();
+        proc.add_transform("Section", |attrs, children| Tree::Inner {
+            tag_name: String::from("section"),
+            attrs,
+            children,
+            span: None,
+        });
+        let mut out = vec![];
+
+        match render_streaming(DOC, &ParseOptions::new(), &proc, &mut out) {
+            Err(StreamError::Unstreamable { tag }) => assert_eq!(tag, "Section"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn each_is_unstreamable_with_data() {
+        let doc = r#"<ul><Each over="rows"><li>{item.name}</li></Each></ul>"#;
+        let mut proc = Processor::new();
+        let mut out = vec![];
+
+        render_streaming(doc, &ParseOptions::new(), &proc, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), render_buffered(doc, &proc));
+
+        let mut data = Data::new();
+        data.insert(String::from("rows"), vec![]);
+        proc.set_data(data);
+        let mut out = vec![];
+
+        match render_streaming(doc, &ParseOptions::new(), &proc, &mut out) {
+            Err(StreamError::Unstreamable { tag }) => assert_eq!(tag, "Each"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn dedented_elements_are_unstreamable() {
+        let mut proc = Processor::new();
+        proc.add_dedent("CodeListing.Racket");
+        let mut out = vec![];
+
+        match render_streaming(DOC, &ParseOptions::new(), &proc, &mut out) {
+            Err(StreamError::Unstreamable { tag }) => assert_eq!(tag, "CodeListing.Racket"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn syntax_errors_are_reported() {
+        let proc = Processor::new();
+        let mut out = vec![];
+
+        match render_streaming("<Doc><Title></Doc>", &ParseOptions::new(), &proc, &mut out) {
+            Err(StreamError::Syntax(errors)) => assert!(!errors.is_empty()),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn parse_options_are_followed() {
+        let doc = "<Doc ;></Doc>";
+        let proc = Processor::new();
+        let mut out = vec![];
+
+        match render_streaming(doc, &ParseOptions::new(), &proc, &mut out) {
+            Err(StreamError::Syntax(errors)) => assert!(!errors.is_empty()),
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        let mut options = ParseOptions::new();
+        options.set_unknown_char_policy(UnknownCharPolicy::Recover);
+        let mut out = vec![];
+
+        render_streaming(doc, &options, &proc, &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "<Doc></Doc>");
+    }
+}
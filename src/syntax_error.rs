@@ -1,10 +1,33 @@
-use crate::common::{Span, FILE_INFO};
+use crate::common::Span;
+use crate::file::SourceFile;
+use std::error::Error;
 use std::fmt;
+use std::sync::Arc;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct SyntaxError {
     pub span: Span,
     pub message: String,
+    pub severity: Severity,
+    pub kind: ErrorKind,
+    // The source the span is in, for display
+    source: Option<Arc<SourceFile>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    /// The input is invalid, whatever might follow it.
+    Invalid,
+    /// The input ended in the middle of the document, so that more input
+    /// might make it valid.
+    UnexpectedEof,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    // Worth reporting, but doesn't prevent the document from being used
+    Warning,
 }
 
 impl SyntaxError {
@@ -15,84 +38,434 @@ impl SyntaxError {
         SyntaxError {
             span,
             message: message.into(),
+            severity: Severity::Error,
+            kind: ErrorKind::Invalid,
+            source: None,
+        }
+    }
+
+    pub fn unexpected_eof<S>(span: Span, message: S) -> Self
+    where
+        S: Into<String>,
+    {
+        SyntaxError {
+            kind: ErrorKind::UnexpectedEof,
+            ..SyntaxError::new(span, message)
+        }
+    }
+
+    pub fn warning<S>(span: Span, message: S) -> Self
+    where
+        S: Into<String>,
+    {
+        SyntaxError {
+            severity: Severity::Warning,
+            ..SyntaxError::new(span, message)
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Attaches the source that the error's span is in, so that the error is
+    /// displayed along with the part of the source it refers to.
+    pub fn with_source(self, source: Arc<SourceFile>) -> Self {
+        SyntaxError {
+            source: Some(source),
+            ..self
+        }
+    }
+
+    pub fn source(&self) -> Option<&SourceFile> {
+        self.source.as_deref()
+    }
+
+    /// Renders the error like `Display` does, but with ANSI escapes that
+    /// color the spanned text red and its carets bright red (for printing to
+    /// a terminal).
+    pub fn render_colored(&self) -> String {
+        let mut out = format!("{}: {}", self.severity, self.message);
+        if let Some(source) = &self.source {
+            out.push('\n');
+            // Writing to a `String` can't fail
+            let _ = write_source(&mut out, source, self.span, true);
+        }
+        out
+    }
+
+    /// Renders the error with `context_lines` lines of the source before and
+    /// after the lines it covers, each preceded by its line number:
+    ///
+    /// ```text
+    /// error: unterminated attribute value
+    ///  1 | <Doc>
+    ///  2 |   <Title lang="en>hi</Title>
+    ///    |               ^^^^
+    ///  3 | </Doc>
+    /// ```
+    ///
+    /// Without a source, only the message is rendered.
+    pub fn render_with_context(&self, context_lines: usize) -> String {
+        let mut out = format!("{}: {}", self.severity, self.message);
+        if let Some(source) = &self.source {
+            // Writing to a `String` can't fail
+            let _ = write_source_with_context(&mut out, source, self.span, context_lines);
+        }
+        out
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
         }
     }
 }
 
+/// Errors are displayed with their severity, their message, and (if they
+/// have a source) the source they cover, underlined.
 impl fmt::Display for SyntaxError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        FILE_INFO.with(|info| {
-            let info = info.borrow();
-            let text = &info.text;
+        write!(f, "{}: {}", self.severity, self.message)?;
+        fmt_source(f, self.source.as_deref(), self.span)
+    }
+}
 
-            let Span { start, end } = self.span;
-            let start_line = pos_to_line(start, text);
-            let end_line = pos_to_line(end, text);
-            let lines = text
-                .lines()
-                .skip(start_line - 1)
-                .take(end_line + 1 - start_line);
+// Errors are equal if they report the same thing at the same place, whether
+// or not they have a source.
+impl PartialEq for SyntaxError {
+    fn eq(&self, other: &Self) -> bool {
+        self.span == other.span
+            && self.message == other.message
+            && self.severity == other.severity
+            && self.kind == other.kind
+    }
+}
 
-            for line in lines {
-                writeln!(f, "{}", line)?;
-                write!(f, " ^^^^^")?;
-            }
+/// The errors that stopped a document from being processed (as returned by
+/// `run`).
+#[derive(Debug)]
+pub struct SyntaxErrors(pub Vec<SyntaxError>);
 
-            Ok(())
-        })
+impl fmt::Display for SyntaxErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for error in &self.0 {
+            writeln!(f, "{}", error)?;
+        }
+        Ok(())
     }
 }
 
-fn pos_to_line(mut pos: usize, source: &str) -> usize {
-    let mut line = 1;
-    let mut chars = source.chars();
+impl Error for SyntaxErrors {}
 
-    while let Some(c) = chars.next() {
-        if pos == 0 {
-            break;
+// Gives each of `errors` the source `input`, if there are any errors.
+pub(crate) fn with_input_source(errors: Vec<SyntaxError>, input: &str) -> Vec<SyntaxError> {
+    if errors.is_empty() {
+        return errors;
+    }
+
+    let source = Arc::new(SourceFile::new("<input>", input));
+    errors
+        .into_iter()
+        .map(|error| error.with_source(Arc::clone(&source)))
+        .collect()
+}
+
+// Writes a line break followed by the lines of `source` covered by `span`,
+// underlining the covered part of each one. Writes nothing without a source.
+pub(crate) fn fmt_source(
+    f: &mut fmt::Formatter,
+    source: Option<&SourceFile>,
+    span: Span,
+) -> fmt::Result {
+    match source {
+        Some(source) => {
+            writeln!(f)?;
+            write_source(f, source, span, false)
         }
+        None => Ok(()),
+    }
+}
 
-        pos -= 1;
-        match c {
-            '\n' => line += 1,
-            '\r' => match chars.next() {
-                // We've just seen a CRLF
-                Some('\n') => {
-                    line += 1;
-                }
-                // We've just seen TWO CRs
-                Some('\r') => {
-                    line += 2;
-                }
-                _ => {}
-            },
-            _ => {}
+const RED: &str = "\x1b[31m";
+const BRIGHT_RED: &str = "\x1b[91m";
+const RESET: &str = "\x1b[0m";
+
+fn write_source<W: fmt::Write>(
+    f: &mut W,
+    source: &SourceFile,
+    span: Span,
+    colored: bool,
+) -> fmt::Result {
+    let text = source.text();
+
+    for (i, line) in covered_lines(source, span).iter().enumerate() {
+        if i > 0 {
+            writeln!(f)?;
+        }
+        let (before, covered, after) = (
+            &text[line.start..line.from],
+            &text[line.from..line.to],
+            &text[line.to..line.end],
+        );
+        if colored {
+            writeln!(f, "{}{}{}{}{}", before, RED, covered, RESET, after)?;
+        } else {
+            writeln!(f, "{}{}{}", before, covered, after)?;
         }
+        write_underline(f, before, covered, colored)?;
     }
 
-    line
+    Ok(())
+}
+
+// Writes the lines covered by `span`, with `context_lines` lines around
+// them, in a gutter of line numbers. Each line (including the first) is
+// preceded by a line break.
+fn write_source_with_context<W: fmt::Write>(
+    f: &mut W,
+    source: &SourceFile,
+    span: Span,
+    context_lines: usize,
+) -> fmt::Result {
+    let text = source.text();
+    let covered = covered_lines(source, span);
+    let first = covered[0].number;
+    let last = covered[covered.len() - 1].number;
+    let shown_from = first.saturating_sub(context_lines).max(1);
+    let shown_to = (last + context_lines).min(source.lines().line_count());
+    let width = shown_to.to_string().len();
+
+    for number in shown_from..=shown_to {
+        let start = source.lines().line_start(number);
+        let end = line_end(text, start);
+        write!(f, "\n {:>width$} |", number, width = width)?;
+        if end > start {
+            write!(f, " {}", &text[start..end])?;
+        }
+
+        if let Some(line) = covered.iter().find(|line| line.number == number) {
+            write!(f, "\n {:width$} | ", "", width = width)?;
+            write_underline(
+                f,
+                &text[line.start..line.from],
+                &text[line.from..line.to],
+                false,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+// A line covered (at least partly) by a span.
+struct CoveredLine {
+    // Starting at 1
+    number: usize,
+    // The offsets of the line's start and end (excluding its line break),
+    // and of the part of it covered
+    start: usize,
+    end: usize,
+    from: usize,
+    to: usize,
+}
+
+// Returns the lines covered by `span`, of which there's always at least one.
+fn covered_lines(source: &SourceFile, span: Span) -> Vec<CoveredLine> {
+    let text = source.text();
+    let lines = source.lines();
+
+    let start = floor_char_boundary(text, span.start);
+    let end = floor_char_boundary(text, span.end.max(span.start));
+    let (start_line, _) = lines.line_col(start);
+    let (end_line, _) = lines.line_col(end);
+
+    let mut covered = vec![];
+    for number in start_line..=end_line {
+        let line_start = lines.line_start(number);
+        let line_end = line_end(text, line_start);
+        let from = start.max(line_start);
+        let to = end.min(line_end);
+
+        // A span ending right at the start of a line doesn't cover any
+        // of it
+        if number > start_line && from == to {
+            break;
+        }
+        covered.push(CoveredLine {
+            number,
+            start: line_start,
+            end: line_end,
+            from,
+            to,
+        });
+    }
+    covered
+}
+
+fn line_end(text: &str, line_start: usize) -> usize {
+    text[line_start..]
+        .find(['\n', '\r'])
+        .map_or(text.len(), |len| line_start + len)
+}
+
+// Writes carets under `covered`, indented past `before` (the part of the line
+// preceding it). Tabs are copied into the indentation, so that the carets line
+// up however wide tabs are displayed.
+fn write_underline<W: fmt::Write>(
+    f: &mut W,
+    before: &str,
+    covered: &str,
+    colored: bool,
+) -> fmt::Result {
+    let indent: String = before
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = covered.chars().count().max(1);
+
+    let carets = "^".repeat(width);
+    if colored {
+        write!(f, "{}{}{}{}", indent, BRIGHT_RED, carets, RESET)
+    } else {
+        write!(f, "{}{}", indent, carets)
+    }
+}
+
+fn floor_char_boundary(source: &str, pos: usize) -> usize {
+    let mut pos = pos.min(source.len());
+    while !source.is_char_boundary(pos) {
+        pos -= 1;
+    }
+    pos
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn in_source(error: SyntaxError, text: &str) -> SyntaxError {
+        error.with_source(Arc::new(SourceFile::new("<test>", text)))
+    }
+
+    #[test]
+    fn underline_matches_span() {
+        let error = in_source(
+            SyntaxError::new(Span::new(19, 23), "unterminated attribute value"),
+            "<Doc>\n\t<Title lang=\"en>hi</Title>\n</Doc>",
+        );
+
+        assert_eq!(
+            error.to_string(),
+            "error: unterminated attribute value\n\t<Title lang=\"en>hi</Title>\n\t            ^^^^"
+        );
+    }
+
+    #[test]
+    fn underline_copies_mixed_tabs_and_spaces() {
+        let error = in_source(
+            SyntaxError::new(Span::new(23, 27), "unterminated attribute value"),
+            "<Doc>\n \t  \t<Title lang=\"en>hi</Title>\n</Doc>",
+        );
+
+        assert_eq!(
+            error.to_string(),
+            "error: unterminated attribute value\n \t  \t<Title lang=\"en>hi</Title>\n \t  \t            ^^^^"
+        );
+    }
+
+    #[test]
+    fn warnings_are_labeled() {
+        let warning = in_source(
+            SyntaxError::warning(Span::new(13, 15), r#"unknown escape sequence "\q""#),
+            r#"<Doc title="a\qb"></Doc>"#,
+        );
+
+        assert_eq!(
+            warning.to_string(),
+            "warning: unknown escape sequence \"\\q\"\n<Doc title=\"a\\qb\"></Doc>\n             ^^"
+        );
+    }
+
+    #[test]
+    fn underline_multi_line_span() {
+        let error = in_source(
+            SyntaxError::new(Span::new(8, 28), "unclosed element"),
+            "<Doc>\n  <Title>hi\n  more\nend</Doc>",
+        );
+
+        assert_eq!(
+            error.to_string(),
+            "error: unclosed element\n  <Title>hi\n  ^^^^^^^^^\n  more\n^^^^^^\nend</Doc>\n^^^"
+        );
+    }
+
+    #[test]
+    fn underline_stops_before_untouched_line() {
+        let error = in_source(SyntaxError::new(Span::new(0, 6), "oops"), "<Doc>\n</Doc>");
+
+        assert_eq!(error.to_string(), "error: oops\n<Doc>\n^^^^^");
+    }
+
+    #[test]
+    fn colored_output_only_wraps_the_span() {
+        let error = in_source(
+            SyntaxError::new(Span::new(19, 23), "unterminated attribute value"),
+            "<Doc>\n\t<Title lang=\"en>hi</Title>\n</Doc>",
+        );
+
+        assert_eq!(
+            error.render_colored(),
+            "error: unterminated attribute value\n\t<Title lang=\x1b[31m\"en>\x1b[0mhi</Title>\n\t            \x1b[91m^^^^\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn context_lines_have_a_gutter() {
+        let source = (1..=12)
+            .map(|n| {
+                if n == 9 {
+                    String::new()
+                } else {
+                    format!("line {}", n)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let start = source.find("line 10").unwrap() + 5;
+        let error = in_source(
+            SyntaxError::new(Span::new(start, start + 2), "oops"),
+            &source,
+        );
+
+        assert_eq!(
+            error.render_with_context(2),
+            "error: oops\n  8 | line 8\n  9 |\n 10 | line 10\n    |      ^^\n 11 | line 11\n 12 | line 12"
+        );
+        assert_eq!(
+            error.render_with_context(0),
+            "error: oops\n 10 | line 10\n    |      ^^"
+        );
+    }
+
     #[test]
-    fn get_line_simple() {
-        let src = "first\nsecond\r\nthird";
-        //         012345 6789012 3 45678
+    fn context_lines_stop_at_the_ends_of_the_source() {
+        let error = in_source(SyntaxError::new(Span::new(2, 8), "unclosed"), "<A>\n<B>\nc");
 
-        assert_eq!(pos_to_line(3, src), 1);
-        assert_eq!(pos_to_line(6, src), 2);
-        assert_eq!(pos_to_line(15, src), 3);
-        assert_eq!(pos_to_line(451, src), 3);
+        assert_eq!(
+            error.render_with_context(3),
+            "error: unclosed\n 1 | <A>\n   |   ^\n 2 | <B>\n   | ^^^\n 3 | c"
+        );
     }
 
     #[test]
-    fn get_line_double_cr() {
-        let src = "first\nsecond\r\rthird";
-        //         012345 6789012 3 45678
+    fn errors_without_a_source_show_only_the_message() {
+        let error = SyntaxError::new(Span::new(0, 6), "oops");
 
-        assert_eq!(pos_to_line(15, src), 4);
+        assert_eq!(error.to_string(), "error: oops");
+        assert_eq!(error.render_colored(), "error: oops");
     }
 }
@@ -1,136 +1,1290 @@
-use super::parser::{Tree as UTree, TreeKind as Tk};
+use super::common::Span;
+use super::parser::{self, ParseOptions, Tree as UTree, TreeKind as Tk, TreeSink};
+use super::render::Renderer;
+use super::syntax_error::{self, SyntaxError};
 use std::fmt;
+use std::str::FromStr;
 
-#[derive(Debug)]
+/// A document tree. Trees are compared without their spans, so a parsed tree
+/// equals the same tree built by hand.
+#[derive(Clone, Debug)]
 pub enum Tree {
-    Text(String),
+    Text {
+        text: String,
+        // Where the text came from in the source, if it was parsed
+        span: Option<Span>,
+    },
+    /// Preformatted text, like the content of a hash-fenced element, whose
+    /// whitespace is significant
+    PreText {
+        text: String,
+        // Where the text came from in the source, if it was parsed
+        span: Option<Span>,
+    },
+    Comment {
+        text: String,
+        span: Option<Span>,
+    },
     Inner {
         tag_name: String,
         attrs: Attrs,
         children: Vec<Tree>,
+        // Where the element came from in the source, if it was parsed
+        span: Option<Span>,
     },
 }
 
-pub type Attrs = Vec<(String, String)>;
-
-impl fmt::Display for Tree {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl PartialEq for Tree {
+    fn eq(&self, other: &Self) -> bool {
         use Tree::*;
 
+        match (self, other) {
+            (Text { text, .. }, Text { text: other, .. })
+            | (PreText { text, .. }, PreText { text: other, .. })
+            | (Comment { text, .. }, Comment { text: other, .. }) => text == other,
+            (
+                Inner {
+                    tag_name,
+                    attrs,
+                    children,
+                    ..
+                },
+                Inner {
+                    tag_name: other_tag_name,
+                    attrs: other_attrs,
+                    children: other_children,
+                    ..
+                },
+            ) => tag_name == other_tag_name && attrs == other_attrs && children == other_children,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Tree {}
+
+pub type Attrs = Vec<(String, AttrValue)>;
+
+/// The name given to a positional attribute: a quoted value written without
+/// a name (like `"photo.jpg"` in `<Img "photo.jpg">`). An element can have at
+/// most one, in any position among its attributes, and it's kept in `Attrs`
+/// in the order written, like any other attribute. A written attribute
+/// named `_` is a syntax error (even if `_` is allowed in names), so it can't
+/// clash with a named attribute, and it's rendered without a name again.
+pub const POSITIONAL_ATTR: &str = "_";
+
+/// Lookups on `Attrs`, which find the (first) attribute with a given name.
+pub trait AttrsExt {
+    /// Returns the value of the `name` attribute, if it has a text value.
+    fn get_text(&self, name: &str) -> Option<&str>;
+
+    /// Returns `true` for a boolean attribute (like `draft` in `<Doc
+    /// draft>`) or a value of `"true"`, and `false` for a value of
+    /// `"false"`. Any other value, or no attribute at all, gives `None`.
+    fn get_bool(&self, name: &str) -> Option<bool>;
+
+    /// Parses the value of the `name` attribute, if it has a text value.
+    fn get_parsed<T: FromStr>(&self, name: &str) -> Option<Result<T, T::Err>>;
+}
+
+impl AttrsExt for [(String, AttrValue)] {
+    fn get_text(&self, name: &str) -> Option<&str> {
+        self.iter()
+            .find(|(attr_name, _)| attr_name == name)
+            .and_then(|(_, value)| value.as_text())
+    }
+
+    fn get_bool(&self, name: &str) -> Option<bool> {
+        match self.iter().find(|(attr_name, _)| attr_name == name)? {
+            (_, AttrValue::Bare) => Some(true),
+            (_, AttrValue::Text(text)) => text.parse().ok(),
+            (_, AttrValue::Tree(_)) => None,
+        }
+    }
+
+    fn get_parsed<T: FromStr>(&self, name: &str) -> Option<Result<T, T::Err>> {
+        self.get_text(name).map(str::parse)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AttrValue {
+    Text(String),
+    /// The value of an element-valued attribute (see
+    /// `ParseOptions::set_element_attr_values`), as parsed
+    Tree(Tree),
+    /// The (lack of a) value of a boolean attribute, like `disabled` in
+    /// `<Input disabled>`
+    Bare,
+}
+
+impl AttrValue {
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            AttrValue::Text(text) => Some(text),
+            AttrValue::Tree(_) | AttrValue::Bare => None,
+        }
+    }
+}
+
+impl From<String> for AttrValue {
+    fn from(text: String) -> Self {
+        AttrValue::Text(text)
+    }
+}
+
+impl From<&str> for AttrValue {
+    fn from(text: &str) -> Self {
+        AttrValue::Text(String::from(text))
+    }
+}
+
+impl Tree {
+    /// Returns a new `tag` element, for building trees without parsing them.
+    pub fn element<S>(tag: S, attrs: Attrs, children: Vec<Tree>) -> Tree
+    where
+        S: Into<String>,
+    {
+        Tree::Inner {
+            tag_name: tag.into(),
+            attrs,
+            children,
+            span: None,
+        }
+    }
+
+    /// Returns a new text node, for building trees without parsing them.
+    pub fn text<S>(text: S) -> Tree
+    where
+        S: Into<String>,
+    {
+        Tree::Text {
+            text: text.into(),
+            span: None,
+        }
+    }
+
+    /// Returns a new `tag` element with the given attributes, containing this
+    /// tree as its only child.
+    pub fn wrap<S>(self, tag: S, attrs: Attrs) -> Tree
+    where
+        S: Into<String>,
+    {
+        Tree::element(tag, attrs, vec![self])
+    }
+
+    /// Replaces this element's children, keeping its tag name, attributes
+    /// and span. Other nodes are returned as they are.
+    pub fn with_children(self, children: Vec<Tree>) -> Tree {
         match self {
-            Text(text) => write!(f, "{}", text),
-            Inner {
+            Tree::Inner {
+                tag_name,
+                attrs,
+                span,
+                ..
+            } => Tree::Inner {
                 tag_name,
                 attrs,
                 children,
-            } => {
-                write!(f, "<{}", tag_name)?;
-                if attrs.len() > 0 {
-                    write!(f, " ")?;
+                span,
+            },
+            tree => tree,
+        }
+    }
+
+    /// Sets the `name` attribute of this element, replacing its value if it
+    /// already has one (and adding it after the others if it doesn't).
+    /// Other nodes are returned as they are.
+    pub fn with_attr<S, V>(mut self, name: S, value: V) -> Tree
+    where
+        S: Into<String>,
+        V: Into<AttrValue>,
+    {
+        if let Tree::Inner { attrs, .. } = &mut self {
+            let name = name.into();
+            let value = value.into();
+            match attrs.iter_mut().find(|(attr_name, _)| *attr_name == name) {
+                Some((_, existing)) => *existing = value,
+                None => attrs.push((name, value)),
+            }
+        }
+        self
+    }
+
+    /// Applies `f` to every node, bottom-up: an element's children are mapped
+    /// before the element itself is. This is the core of what a `Processor`
+    /// does, minus the lookup of transforms by tag name and the reprocessing
+    /// of their output.
+    pub fn map<F>(self, f: &mut F) -> Tree
+    where
+        F: FnMut(Tree) -> Tree,
+    {
+        let tree = match self {
+            Tree::Inner {
+                tag_name,
+                attrs,
+                children,
+                span,
+            } => Tree::Inner {
+                tag_name,
+                attrs,
+                children: children.into_iter().map(|child| child.map(f)).collect(),
+                span,
+            },
+            text => text,
+        };
+
+        f(tree)
+    }
+
+    /// Rewrites the text of every text node (preformatted or not) with `f`,
+    /// leaving everything else, including attribute values, as it is.
+    pub fn map_text<F>(self, f: F) -> Tree
+    where
+        F: Fn(String) -> String,
+    {
+        self.map(&mut |tree| match tree {
+            Tree::Text { text, span } => Tree::Text {
+                text: f(text),
+                span,
+            },
+            Tree::PreText { text, span } => Tree::PreText {
+                text: f(text),
+                span,
+            },
+            tree => tree,
+        })
+    }
+
+    /// Accumulates a value over every node, visited in the order `iter`
+    /// visits them.
+    pub fn fold<B, F>(&self, init: B, f: F) -> B
+    where
+        F: Fn(B, &Tree) -> B,
+    {
+        self.iter().fold(init, f)
+    }
+
+    /// Collapses each run of whitespace in text to a single space, and trims
+    /// the whitespace from the ends of each text node. Text nodes that are
+    /// left empty (including those that were only whitespace, like the
+    /// indentation between elements) are dropped. Preformatted text is left
+    /// as it is.
+    pub fn normalize_whitespace(self) -> Tree {
+        match self {
+            Tree::Text { text, span } => Tree::Text {
+                text: collapse_whitespace(&text),
+                span,
+            },
+            Tree::Inner {
+                tag_name,
+                attrs,
+                children,
+                span,
+            } => Tree::Inner {
+                tag_name,
+                attrs,
+                children: children
+                    .into_iter()
+                    .map(Tree::normalize_whitespace)
+                    .filter(|child| !matches!(child, Tree::Text { text, .. } if text.is_empty()))
+                    .collect(),
+                span,
+            },
+            tree => tree,
+        }
+    }
+
+    /// Returns where this node came from in the source, if it was parsed.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Tree::Text { span, .. }
+            | Tree::PreText { span, .. }
+            | Tree::Comment { span, .. }
+            | Tree::Inner { span, .. } => *span,
+        }
+    }
+
+    /// Returns this element's tag name, or `None` for other nodes.
+    pub fn tag_name(&self) -> Option<&str> {
+        match self {
+            Tree::Inner { tag_name, .. } => Some(tag_name),
+            _ => None,
+        }
+    }
+
+    /// Returns the prefix of this element's tag name, if it has one: `math`
+    /// for `<math:Equation>`.
+    pub fn tag_prefix(&self) -> Option<&str> {
+        match self {
+            Tree::Inner { tag_name, .. } => tag_name.split_once(':').map(|(prefix, _)| prefix),
+            _ => None,
+        }
+    }
+
+    /// Returns this element's tag name without its prefix: `Equation` for
+    /// `<math:Equation>`.
+    pub fn local_name(&self) -> Option<&str> {
+        match self {
+            Tree::Inner { tag_name, .. } => Some(
+                tag_name
+                    .split_once(':')
+                    .map_or(tag_name.as_str(), |(_, local_name)| local_name),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Iterates over every node in this tree (including the tree itself) in
+    /// document order, visiting each element before its children.
+    /// Attribute values aren't visited.
+    pub fn iter(&self) -> impl Iterator<Item = &Tree> {
+        let mut stack = vec![self];
+        std::iter::from_fn(move || {
+            let tree = stack.pop()?;
+            if let Tree::Inner { children, .. } = tree {
+                stack.extend(children.iter().rev());
+            }
+            Some(tree)
+        })
+    }
+
+    /// Iterates over the elements in this tree, like `iter`, along with
+    /// their tag names.
+    pub fn iter_inner(&self) -> impl Iterator<Item = (&str, &Tree)> {
+        self.iter().filter_map(|tree| match tree {
+            Tree::Inner { tag_name, .. } => Some((tag_name.as_str(), tree)),
+            _ => None,
+        })
+    }
+
+    /// Returns the `tag` elements in this tree (including the tree itself),
+    /// in document order.
+    pub fn find_all(&self, tag: &str) -> Vec<&Tree> {
+        self.iter_inner()
+            .filter(|(tag_name, _)| *tag_name == tag)
+            .map(|(_, tree)| tree)
+            .collect()
+    }
+
+    /// Returns the first `tag` element in this tree (including the tree
+    /// itself), in document order.
+    pub fn find_first(&self, tag: &str) -> Option<&Tree> {
+        self.iter_inner()
+            .find(|(tag_name, _)| *tag_name == tag)
+            .map(|(_, tree)| tree)
+    }
+
+    /// Returns the text in this tree (including preformatted text), in
+    /// document order, without any tags or comments.
+    pub fn text_content(&self) -> String {
+        self.iter()
+            .filter_map(|tree| match tree {
+                Tree::Text { text, .. } | Tree::PreText { text, .. } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Renders this tree with each element on its own line, and children
+    /// indented by `indent` spaces (see `Renderer::set_indent`).
+    pub fn to_pretty_string(&self, indent: usize) -> String {
+        let mut renderer = Renderer::new();
+        renderer.set_indent(Some(indent));
+        renderer.render(self)
+    }
+
+    /// Returns a value that displays this tree with its text and attribute
+    /// values HTML-escaped. Plain `Display` writes them verbatim.
+    pub fn display_escaped(&self) -> DisplayEscaped<'_> {
+        DisplayEscaped(self)
+    }
+
+    /// Returns this tree as JSON. Elements become
+    /// `{"tag":"Doc","attrs":{...},"children":[...]}` (with their attributes
+    /// in order), text becomes `{"text":"..."}`, and comments become
+    /// `{"comment":"..."}`. Boolean attributes have the value `true`, and
+    /// element-valued attributes have their element's JSON as their value.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        write_json(&mut out, self);
+        out
+    }
+}
+
+fn write_json(out: &mut String, tree: &Tree) {
+    match tree {
+        Tree::Text { text, .. } | Tree::PreText { text, .. } => {
+            out.push_str("{\"text\":");
+            write_json_string(out, text);
+            out.push('}');
+        }
+        Tree::Comment { text, .. } => {
+            out.push_str("{\"comment\":");
+            write_json_string(out, text);
+            out.push('}');
+        }
+        Tree::Inner {
+            tag_name,
+            attrs,
+            children,
+            ..
+        } => {
+            out.push_str("{\"tag\":");
+            write_json_string(out, tag_name);
+
+            out.push_str(",\"attrs\":{");
+            for (i, (name, value)) in attrs.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
                 }
-                for attr in attrs {
-                    let (name, value) = attr;
-                    write!(f, "{}=\"{}\"", name, value)?;
+                write_json_string(out, name);
+                out.push(':');
+                match value {
+                    AttrValue::Text(text) => write_json_string(out, text),
+                    AttrValue::Tree(tree) => write_json(out, tree),
+                    AttrValue::Bare => out.push_str("true"),
                 }
-                write!(f, ">")?;
+            }
 
-                for child in children {
-                    child.fmt(f)?;
+            out.push_str("},\"children\":[");
+            for (i, child) in children.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
                 }
-
-                write!(f, "</{}>", tag_name)?;
-
-                Ok(())
+                write_json(out, child);
             }
+            out.push_str("]}");
         }
     }
 }
 
-impl From<UTree> for Tree {
-    fn from(tree: UTree) -> Self {
-        match tree.kind {
-            Tk::Document => parse_doc(tree),
-            _ => panic!("expected document"),
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
         }
     }
+    out.push('"');
 }
 
-fn parse_doc(mut tree: UTree) -> Tree {
-    assert_eq!(tree.kind, Tk::Document);
-
-    let inner = tree.children.pop().expect("expected child");
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
 
-    if tree.children.len() > 0 {
-        panic!("expected single child")
+/// The alternate flag (`{:#}`) pretty-prints the tree, as
+/// `to_pretty_string(2)` does.
+impl fmt::Display for Tree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut renderer = Renderer::new();
+        if f.alternate() {
+            renderer.set_indent(Some(2));
+        }
+        renderer.write(f, self)
     }
-
-    parse_inner(inner)
 }
 
-fn parse_inner(mut tree: UTree) -> Tree {
-    assert_eq!(tree.kind, Tk::InnerNode);
+/// Displays a tree like its `Display` impl does, but with markup characters
+/// in text and attribute values escaped (see `Renderer::set_escape_html`).
+/// Returned by `Tree::display_escaped`.
+pub struct DisplayEscaped<'a>(&'a Tree);
 
-    let end = tree.children.len() - 1;
-    tree.children.swap(0, end);
+impl<'a> fmt::Display for DisplayEscaped<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut renderer = Renderer::new();
+        renderer.set_escape_html(true);
+        renderer.write(f, self.0)
+    }
+}
 
-    let open_tag = tree.children.pop().expect("expected open tag");
-    let open_tag = parse_open_tag(open_tag);
+/// Converts a syntax tree (a document or an element) into a `Tree`. The
+/// conversion never fails: the parts of a syntax tree with errors that are
+/// missing (like the name of an element whose open tag was cut off) are
+/// left empty, and a document without a root becomes an element with an
+/// empty name.
+impl From<UTree> for Tree {
+    fn from(tree: UTree) -> Self {
+        match tree.kind {
+            Tk::Document => parse_doc(tree),
+            _ => parse_node(tree).unwrap_or_else(|| Tree::element("", vec![], vec![])),
+        }
+    }
+}
 
-    let close_tag = tree.children.get(0).expect("expected close tag");
-    assert_eq!(close_tag.kind, Tk::CloseTag);
+fn parse_doc(tree: UTree) -> Tree {
+    // The parser reports any roots after the first
+    tree.children
+        .into_iter()
+        .find(|child| child.kind == Tk::InnerNode)
+        .map_or_else(|| Tree::element("", vec![], vec![]), parse_inner)
+}
 
-    let children = tree.children.into_iter().skip(1).map(parse_node).collect();
+fn parse_inner(tree: UTree) -> Tree {
+    let mut open_tag = OpenTag {
+        name: String::new(),
+        attrs: vec![],
+    };
+    let mut children = vec![];
+    for child in tree.children {
+        match child.kind {
+            Tk::OpenTag => open_tag = parse_open_tag(child),
+            // Self-closing elements don't have a close tag, and recovered
+            // ones have an empty one
+            Tk::CloseTag => {}
+            _ => children.extend(parse_node(child)),
+        }
+    }
 
     Tree::Inner {
         tag_name: open_tag.name,
         attrs: open_tag.attrs,
         children,
+        span: Some(tree.span),
     }
 }
 
-fn parse_node(tree: UTree) -> Tree {
-    match tree.kind {
+// Returns `None` for anything that isn't a node of its own.
+fn parse_node(tree: UTree) -> Option<Tree> {
+    let node = match tree.kind {
         Tk::InnerNode => parse_inner(tree),
-        Tk::TextNode(content) => Tree::Text(content),
-        _ => panic!("expected inner node or text"),
+        Tk::TextNode(content) => Tree::Text {
+            text: content,
+            span: Some(tree.span),
+        },
+        Tk::RawTextNode(content) => Tree::PreText {
+            text: content,
+            span: Some(tree.span),
+        },
+        Tk::Comment(text) => Tree::Comment {
+            text,
+            span: Some(tree.span),
+        },
+        _ => return None,
+    };
+    Some(node)
+}
+
+fn parse_open_tag(tree: UTree) -> OpenTag {
+    let mut open_tag = OpenTag {
+        name: String::new(),
+        attrs: vec![],
+    };
+    for child in tree.children {
+        match child.kind {
+            Tk::TagName(name) => open_tag.name = name,
+            Tk::Attrs => open_tag.attrs = parse_attrs(child),
+            _ => {}
+        }
     }
+    open_tag
 }
 
-fn parse_open_tag(mut tree: UTree) -> OpenTag {
-    assert_eq!(tree.kind, Tk::OpenTag);
+fn parse_attrs(tree: UTree) -> Attrs {
+    let mut attrs: Attrs = vec![];
+    for (name, value) in tree.children.into_iter().filter_map(parse_attr) {
+        // The parser reports duplicates; the first one wins
+        if !attrs.iter().any(|(attr_name, _)| *attr_name == name) {
+            attrs.push((name, value));
+        }
+    }
+    attrs
+}
 
-    let attrs = tree.children.pop().expect("expected attributes");
-    let tag_name = tree.children.pop().expect("expected tag name");
+// Returns `None` for an attribute without a name.
+fn parse_attr(tree: UTree) -> Option<(String, AttrValue)> {
+    let mut name = None;
+    let mut value = AttrValue::Bare;
+    for child in tree.children {
+        match child.kind {
+            Tk::AttrName(attr_name) => name = Some(attr_name),
+            Tk::AttrVal(attr_val) => value = AttrValue::Text(attr_val),
+            Tk::InnerNode => value = AttrValue::Tree(parse_inner(child)),
+            _ => {}
+        }
+    }
+    name.map(|name| (name, value))
+}
 
-    let attrs = parse_attrs(attrs);
+/// Escapes `text` so that it's parsed back as the same text node content:
+/// `&` and `<` are replaced with entities.
+pub fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
 
-    match tag_name.kind {
-        Tk::TagName(name) => OpenTag { name, attrs },
-        _ => panic!("expected tag name kind"),
+/// Escapes `value` so that, written between double quotes, it's parsed back
+/// as the same attribute value: quotes and backslashes are escaped with a
+/// backslash, line breaks (which would end the value) are written as escapes
+/// or entities, and `&` is replaced with an entity.
+pub fn escape_attr(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("&#13;"),
+            '&' => escaped.push_str("&amp;"),
+            c => escaped.push(c),
+        }
     }
+    escaped
 }
 
-fn parse_attrs(tree: UTree) -> Attrs {
-    assert_eq!(tree.kind, Tk::Attrs);
+struct OpenTag {
+    name: String,
+    attrs: Attrs,
+}
+
+/// Parses `input` directly into a `Tree`, without building the intermediate
+/// syntax tree that `parser::parse` produces (and so without the spans of
+/// anything but nodes, like tags and attributes).
+pub fn parse_to_tree(input: &str, options: &ParseOptions) -> Result<Tree, Vec<SyntaxError>> {
+    let mut builder = DirectBuilder {
+        options,
+        frames: vec![],
+        roots: vec![],
+        errors: vec![],
+    };
+
+    parser::parse_into(input, &mut builder);
 
-    tree.children.into_iter().map(parse_attr).collect()
+    if builder.errors.iter().any(SyntaxError::is_error) {
+        builder.errors.sort_by_key(|error| error.span);
+        return Err(syntax_error::with_input_source(builder.errors, input));
+    }
+    Ok(builder.roots.pop().expect("expected root element"))
 }
 
-fn parse_attr(mut tree: UTree) -> (String, String) {
-    assert_eq!(tree.kind, Tk::Attr);
+// Builds a `Tree` from the nodes reported by the parser, keeping only the
+// nodes currently open.
+struct DirectBuilder<'a> {
+    options: &'a ParseOptions,
+    frames: Vec<Frame>,
+    roots: Vec<Tree>,
+    errors: Vec<SyntaxError>,
+}
 
-    let value = tree.children.pop().expect("expected attribute value");
-    let name = tree.children.pop().expect("expected attribute name");
+enum Frame {
+    InnerNode {
+        start: usize,
+        tag_name: String,
+        attrs: Attrs,
+        children: Vec<Tree>,
+    },
+    Attr(String, AttrValue),
+    Other,
+}
 
-    match (name.kind, value.kind) {
-        (Tk::AttrName(name), Tk::AttrVal(value)) => (name, value),
-        (Tk::AttrName(_), _) => panic!("expected attribute value kind"),
-        (_, Tk::AttrVal(_)) => panic!("expected attribute name kind"),
-        _ => panic!("expected attribute name and attribute value kinds"),
+impl<'a> DirectBuilder<'a> {
+    fn current_element(&mut self) -> Option<(&mut String, &mut Attrs, &mut Vec<Tree>)> {
+        self.frames.iter_mut().rev().find_map(|frame| match frame {
+            Frame::InnerNode {
+                tag_name,
+                attrs,
+                children,
+                ..
+            } => Some((tag_name, attrs, children)),
+            _ => None,
+        })
     }
 }
 
-struct OpenTag {
-    name: String,
-    attrs: Attrs,
+impl<'a> TreeSink for DirectBuilder<'a> {
+    fn open(&mut self, kind: Tk, start: usize) {
+        let frame = match kind {
+            Tk::InnerNode => Frame::InnerNode {
+                start,
+                tag_name: String::new(),
+                attrs: vec![],
+                children: vec![],
+            },
+            Tk::Attr => Frame::Attr(String::new(), AttrValue::Bare),
+            _ => Frame::Other,
+        };
+        self.frames.push(frame);
+    }
+
+    fn add_leaf(&mut self, kind: Tk, span: Span) {
+        match (self.frames.last_mut(), kind) {
+            (Some(Frame::Attr(name, _)), Tk::AttrName(attr_name)) => *name = attr_name,
+            (Some(Frame::Attr(_, value)), Tk::AttrVal(attr_val)) => {
+                *value = AttrValue::Text(attr_val)
+            }
+            (_, Tk::TagName(name)) => {
+                // Close tag names are checked by the parser, so only the
+                // open tag's name is needed
+                if let Some((tag_name, _, _)) = self.current_element() {
+                    if tag_name.is_empty() {
+                        *tag_name = name;
+                    }
+                }
+            }
+            (Some(Frame::InnerNode { children, .. }), Tk::TextNode(text)) => {
+                children.push(Tree::Text {
+                    text,
+                    span: Some(span),
+                });
+            }
+            (Some(Frame::InnerNode { children, .. }), Tk::RawTextNode(text)) => {
+                children.push(Tree::PreText {
+                    text,
+                    span: Some(span),
+                });
+            }
+            (Some(Frame::InnerNode { children, .. }), Tk::Comment(text)) => {
+                children.push(Tree::Comment {
+                    text,
+                    span: Some(span),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn complete_with(&mut self, end: usize, _recovered: bool) {
+        match self.frames.pop() {
+            Some(Frame::InnerNode {
+                start,
+                tag_name,
+                attrs,
+                children,
+            }) => {
+                let tree = Tree::Inner {
+                    tag_name,
+                    attrs,
+                    children,
+                    span: Some(Span::new(start, end)),
+                };
+                if let Some(Frame::Attr(_, value)) = self.frames.last_mut() {
+                    *value = AttrValue::Tree(tree);
+                    return;
+                }
+                match self.current_element() {
+                    Some((_, _, children)) => children.push(tree),
+                    None => self.roots.push(tree),
+                }
+            }
+            Some(Frame::Attr(name, value)) => {
+                if let Some((_, attrs, _)) = self.current_element() {
+                    attrs.push((name, value));
+                }
+            }
+            Some(Frame::Other) => {}
+            None => panic!("no open item to complete"),
+        }
+    }
+
+    fn add_error(&mut self, error: SyntaxError) {
+        self.errors.push(error);
+    }
+
+    fn options(&self) -> &ParseOptions {
+        self.options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, ParseOptions};
+    use crate::processor::Processor;
+
+    fn parse_tree(src: &str) -> Tree {
+        let result = parse(src, &ParseOptions::new());
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        Tree::from(result.tree)
+    }
+
+    #[test]
+    fn escaped_text_and_attrs_parse_back() {
+        for raw in &[
+            "plain",
+            "a < b && c",
+            "&amp; \"x\" 'y' \\n",
+            "line\nbreak\r\n\ttab",
+            "<!-- -->",
+        ] {
+            let src = format!(
+                r#"<Doc v="{}">{}</Doc>"#,
+                escape_attr(raw),
+                escape_text(raw)
+            );
+            let tree = parse_tree(&src);
+
+            assert_eq!(tree.text_content(), *raw);
+            match tree {
+                Tree::Inner { attrs, .. } => assert_eq!(attrs.get_text("v"), Some(*raw)),
+                tree => panic!("unexpected tree: {:?}", tree),
+            }
+        }
+    }
+
+    #[test]
+    fn dotted_attribute_names_are_literal() {
+        let tree = parse_tree(r#"<Doc data.id="x" data.ref.id="y"></Doc>"#);
+
+        match tree {
+            Tree::Inner { attrs, .. } => {
+                let attrs: Vec<(&str, Option<&str>)> = attrs
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_text()))
+                    .collect();
+                assert_eq!(
+                    attrs,
+                    vec![("data.id", Some("x")), ("data.ref.id", Some("y"))]
+                )
+            }
+            _ => panic!("expected inner node"),
+        }
+    }
+
+    #[test]
+    fn converting_after_errors_does_not_panic() {
+        let convert = |src| Tree::from(parse(src, &ParseOptions::new()).tree).to_string();
+
+        assert_eq!(convert(""), "<></>");
+        assert_eq!(convert("<"), "<></>");
+        assert_eq!(convert("<Doc"), "<Doc></Doc>");
+        assert_eq!(convert("<Doc a="), "<Doc a></Doc>");
+        assert_eq!(convert("<Doc =\"x\">a"), "<Doc>a</Doc>");
+        assert_eq!(convert("<Doc><Title>a</"), "<Doc><Title>a</Title></Doc>");
+        assert_eq!(convert("</Doc>"), "<></>");
+    }
+
+    #[test]
+    fn deep_trees_display_without_recursing() {
+        let depth = 10_000;
+        let mut tree = Tree::text("x");
+        for _ in 0..depth {
+            tree = tree.wrap("A", vec![]);
+        }
+
+        // Too small a stack for a recursive `Display`
+        let out = std::thread::scope(|scope| {
+            std::thread::Builder::new()
+                .stack_size(64 * 1024)
+                .spawn_scoped(scope, || tree.to_string())
+                .unwrap()
+                .join()
+                .unwrap()
+        });
+        assert_eq!(
+            out,
+            format!("{}x{}", "<A>".repeat(depth), "</A>".repeat(depth))
+        );
+    }
+
+    #[test]
+    fn text_keeps_its_span() {
+        let tree = parse_tree("<Doc>a &amp; b<Title>c</Title><Code #>d</# Code></Doc>");
+
+        match tree {
+            Tree::Inner { children, .. } => {
+                // The span doesn't affect equality
+                assert_eq!(children[0], Tree::text("a & b"));
+                assert_eq!(children[0].span(), Some(Span::new(5, 14)));
+
+                let code = children[2].find_first("Code").unwrap();
+                assert_eq!(code.iter().nth(1).unwrap().span(), Some(Span::new(38, 39)));
+            }
+            _ => panic!("expected inner node"),
+        }
+    }
+
+    #[test]
+    fn builder_style_changes() {
+        let section = parse_tree(r#"<Section id="a"><p>old</p></Section>"#)
+            .with_children(vec![Tree::text("new")])
+            .with_attr("id", "b")
+            .with_attr("draft", AttrValue::Bare);
+
+        assert_eq!(section.tag_name(), Some("Section"));
+        assert_eq!(
+            section.to_string(),
+            r#"<Section id="b" draft>new</Section>"#
+        );
+
+        let text = Tree::text("x").with_children(vec![]).with_attr("id", "a");
+        assert_eq!(text, Tree::text("x"));
+        assert_eq!(text.tag_name(), None);
+    }
+
+    #[test]
+    fn wrap_nests_node_in_new_parent() {
+        let table = parse_tree("<table><tr><td>1</td></tr></table>");
+        let wrapped = table.wrap("div", vec![(String::from("class"), "scroll".into())]);
+
+        assert_eq!(
+            wrapped.to_string(),
+            r#"<div class="scroll"><table><tr><td>1</td></tr></table></div>"#
+        );
+    }
+
+    const DOC: &str = r#"<Doc>
+  <Title lang="en" id="title">My first doc</Title>
+  <Section ref="sec-1">
+    Here's how <Mono>map</Mono> &amp; <Mono>filter</Mono> look:
+    <CodeListing.Racket #>(map f (filter p xs))</# CodeListing.Racket>
+  </Section>
+</Doc>"#;
+
+    #[test]
+    fn parse_to_tree_matches_two_pass_parse() {
+        let direct = parse_to_tree(DOC, &ParseOptions::new()).unwrap();
+        let two_pass = parse_tree(DOC);
+
+        assert_eq!(direct, two_pass);
+        assert!(direct
+            .iter()
+            .map(Tree::span)
+            .eq(two_pass.iter().map(Tree::span)));
+    }
+
+    #[test]
+    fn parse_to_tree_reports_errors() {
+        let errors = parse_to_tree("<Doc><Title></Doc>", &ParseOptions::new()).unwrap_err();
+        assert!(!errors.is_empty());
+    }
+
+    #[test]
+    fn map_uppercases_tag_names() {
+        let tree = parse_tree("<Doc><Title>Hi</Title><Section><Mono>x</Mono></Section></Doc>");
+
+        let mut visited = vec![];
+        let tree = tree.map(&mut |node| match node {
+            Tree::Inner {
+                tag_name,
+                attrs,
+                children,
+                span,
+            } => {
+                visited.push(tag_name.clone());
+                Tree::Inner {
+                    tag_name: tag_name.to_uppercase(),
+                    attrs,
+                    children,
+                    span,
+                }
+            }
+            text => text,
+        });
+
+        assert_eq!(
+            tree.to_string(),
+            "<DOC><TITLE>Hi</TITLE><SECTION><MONO>x</MONO></SECTION></DOC>"
+        );
+        assert_eq!(visited, vec!["Title", "Mono", "Section", "Doc"]);
+    }
+
+    #[test]
+    fn map_matches_processor_for_non_recursive_transforms() {
+        fn transform_section(attrs: Attrs, children: Vec<Tree>) -> Tree {
+            Tree::Inner {
+                tag_name: String::from("section"),
+                attrs,
+                children,
+                span: None,
+            }
+        }
+
+        let src = "<Doc><Section>a</Section><Section>b</Section></Doc>";
+
+        let mut proc = Processor::new();
+        proc.add_transform("Section", transform_section);
+        let processed = proc.process(parse_tree(src)).unwrap();
+
+        let mapped = parse_tree(src).map(&mut |node| match node {
+            Tree::Inner {
+                tag_name,
+                attrs,
+                children,
+                ..
+            } if tag_name == "Section" => transform_section(attrs, children),
+            node => node,
+        });
+
+        assert_eq!(mapped.to_string(), processed.to_string());
+    }
+
+    #[test]
+    fn element_valued_attributes() {
+        let src = r#"<Cell content=<b>x</b> width="2"></Cell>"#;
+        let mut options = ParseOptions::new();
+        options.set_element_attr_values(true);
+
+        let result = parse(src, &options);
+        assert!(result.errors.is_empty(), "{:?}", result.errors);
+        let tree = Tree::from(result.tree);
+
+        match &tree {
+            Tree::Inner { attrs, .. } => {
+                assert_eq!(attrs[0].0, "content");
+                match &attrs[0].1 {
+                    AttrValue::Tree(Tree::Inner {
+                        tag_name, children, ..
+                    }) => {
+                        assert_eq!(tag_name, "b");
+                        assert_eq!(children.len(), 1);
+                    }
+                    value => panic!("expected element value, found {:?}", value),
+                }
+                assert_eq!(attrs[1].1.as_text(), Some("2"));
+            }
+            _ => panic!("expected inner node"),
+        }
+        assert_eq!(tree.to_string(), src);
+
+        let direct = parse_to_tree(src, &options).unwrap();
+        assert_eq!(direct, tree);
+    }
+
+    #[test]
+    fn element_valued_attributes_are_opt_in() {
+        let src = "<Cell content=<b>x</b>></Cell>";
+
+        let result = parse(src, &ParseOptions::new());
+        assert!(result
+            .errors
+            .iter()
+            .any(|error| error.message == "expected attribute value"));
+    }
+
+    #[test]
+    fn comments_keep_their_position() {
+        let src = "<a><!--x--><b></b></a>";
+        let tree = parse_tree(src);
+
+        match &tree {
+            Tree::Inner { children, .. } => match &children[..] {
+                [Tree::Comment { text, span }, Tree::Inner { tag_name, .. }] => {
+                    assert_eq!(text, "x");
+                    assert_eq!(*span, Some(Span::new(3, 11)));
+                    assert_eq!(tag_name, "b");
+                }
+                children => panic!("unexpected children: {:?}", children),
+            },
+            _ => panic!("expected inner node"),
+        }
+        assert_eq!(tree.to_string(), src);
+
+        let src = "<a>one<b></b><!-- two --> three</a>";
+        assert_eq!(parse_tree(src).to_string(), src);
+    }
+
+    #[test]
+    fn find_elements_by_tag() {
+        let tree = parse_tree(
+            "<Doc><CodeListing.Haskell>a</CodeListing.Haskell>\
+             <Section><CodeListing.Haskell>b</CodeListing.Haskell></Section></Doc>",
+        );
+
+        let texts: Vec<String> = tree
+            .find_all("CodeListing.Haskell")
+            .into_iter()
+            .map(|listing| listing.to_string())
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                "<CodeListing.Haskell>a</CodeListing.Haskell>",
+                "<CodeListing.Haskell>b</CodeListing.Haskell>"
+            ]
+        );
+
+        assert_eq!(
+            tree.find_first("CodeListing.Haskell").unwrap().to_string(),
+            "<CodeListing.Haskell>a</CodeListing.Haskell>"
+        );
+        assert!(tree.find_first("Doc").is_some());
+        assert!(tree.find_first("Missing").is_none());
+        assert!(tree.find_all("Missing").is_empty());
+    }
+
+    #[test]
+    fn text_content_skips_tags_and_comments() {
+        let tree = parse_tree(
+            "<Doc><Title>My <Em>first</Em> doc</Title><!-- draft --><Code #>x < y</# Code></Doc>",
+        );
+
+        assert_eq!(tree.text_content(), "My first docx < y");
+        assert_eq!(
+            tree.find_first("Title").unwrap().text_content(),
+            "My first doc"
+        );
+    }
+
+    #[test]
+    fn to_json_keeps_attribute_order_and_escapes() {
+        let tree = parse_tree(
+            "<Doc title=\"A &quot;doc&quot;\" draft lang=\"en\">line\n\t\"quoted\"\u{1}<!-- c --></Doc>",
+        );
+
+        assert_eq!(
+            tree.to_json(),
+            r#"{"tag":"Doc","attrs":{"title":"A \"doc\"","draft":true,"lang":"en"},"children":[{"text":"line\n\t\"quoted\"\u0001"},{"comment":" c "}]}"#
+        );
+    }
+
+    #[test]
+    fn normalize_whitespace_leaves_pre_text_alone() {
+        let tree = parse_tree(
+            "<Doc>\n  <Title>  My\n    first   doc </Title>\n\n  <Code #>\n  (f  x)\n</# Code>\n</Doc>",
+        );
+
+        assert_eq!(
+            tree.normalize_whitespace().to_string(),
+            "<Doc><Title>My first doc</Title><Code>\n  (f  x)\n</Code></Doc>"
+        );
+    }
+
+    #[test]
+    fn prefixed_tag_names() {
+        let tree = parse_tree("<Doc><math:Equation>x</math:Equation></Doc>");
+        let equation = tree.find_first("math:Equation").unwrap();
+
+        assert_eq!(equation.tag_prefix(), Some("math"));
+        assert_eq!(equation.local_name(), Some("Equation"));
+        assert_eq!(tree.tag_prefix(), None);
+        assert_eq!(tree.local_name(), Some("Doc"));
+    }
+
+    #[test]
+    fn attribute_accessors() {
+        let tree = parse_tree(r#"<Doc title="Notes" draft year="2021" toc="false" n="x"></Doc>"#);
+        let attrs = match &tree {
+            Tree::Inner { attrs, .. } => attrs,
+            _ => panic!("expected inner node"),
+        };
+
+        assert_eq!(attrs.get_text("title"), Some("Notes"));
+        assert_eq!(attrs.get_text("draft"), None);
+        assert_eq!(attrs.get_text("missing"), None);
+
+        assert_eq!(attrs.get_bool("draft"), Some(true));
+        assert_eq!(attrs.get_bool("toc"), Some(false));
+        assert_eq!(attrs.get_bool("title"), None);
+        assert_eq!(attrs.get_bool("missing"), None);
+
+        assert_eq!(attrs.get_parsed::<u32>("year"), Some(Ok(2021)));
+        assert!(attrs.get_parsed::<u32>("n").unwrap().is_err());
+        assert_eq!(attrs.get_parsed::<u32>("missing"), None);
+    }
+
+    #[test]
+    fn iter_visits_nodes_in_pre_order() {
+        let tree =
+            parse_tree("<Doc><Title>Hi</Title>text<!--c--><Section><P>x</P></Section></Doc>");
+
+        let nodes: Vec<String> = tree
+            .iter()
+            .map(|node| match node {
+                Tree::Inner { tag_name, .. } => tag_name.clone(),
+                Tree::Text { text, .. } | Tree::PreText { text, .. } => format!("{:?}", text),
+                Tree::Comment { text, .. } => format!("<!--{}-->", text),
+            })
+            .collect();
+        assert_eq!(
+            nodes,
+            vec!["Doc", "Title", "\"Hi\"", "\"text\"", "<!--c-->", "Section", "P", "\"x\""]
+        );
+
+        let tags: Vec<&str> = tree.iter_inner().map(|(tag, _)| tag).collect();
+        assert_eq!(tags, vec!["Doc", "Title", "Section", "P"]);
+    }
+
+    #[test]
+    fn map_text_and_fold() {
+        let tree =
+            parse_tree(r#"<Doc title="a title"><Title>My doc</Title><P>has three words</P></Doc>"#);
+
+        let words = |tree: &Tree| {
+            tree.fold(0, |count, node| match node {
+                Tree::Text { text, .. } => count + text.split_whitespace().count(),
+                _ => count,
+            })
+        };
+        assert_eq!(words(&tree), 5);
+
+        let shouted = tree.map_text(|text| text.to_uppercase());
+        assert_eq!(
+            shouted.to_string(),
+            r#"<Doc title="a title"><Title>MY DOC</Title><P>HAS THREE WORDS</P></Doc>"#
+        );
+        assert_eq!(words(&shouted), 5);
+    }
+
+    #[test]
+    fn trees_compare_structurally() {
+        let tree = parse_tree(r#"<Doc draft><Title lang="en">Hi</Title></Doc>"#);
+        let snapshot = tree.clone();
+        assert_eq!(tree, snapshot);
+
+        let changed = tree.map_text(|text| text + "!");
+        assert_ne!(changed, snapshot);
+
+        let built = Tree::element(
+            "Doc",
+            vec![(String::from("draft"), AttrValue::Bare)],
+            vec![Tree::element(
+                "Title",
+                vec![(String::from("lang"), AttrValue::from("en"))],
+                vec![Tree::text("Hi")],
+            )],
+        );
+        assert_eq!(snapshot, built);
+    }
+
+    #[test]
+    fn pretty_printing() {
+        let tree = parse_tree(
+            "<Doc><Title>My doc</Title><Section>Intro <Mono>map</Mono>\n  <!--todo--><Code #>\n  (f x)\n</# Code></Section><Empty></Empty></Doc>",
+        );
+
+        let expected = "<Doc>
+  <Title>My doc</Title>
+  <Section>
+    Intro
+    <Mono>map</Mono>
+    <!--todo-->
+    <Code>
+  (f x)
+</Code>
+  </Section>
+  <Empty></Empty>
+</Doc>";
+        assert_eq!(tree.to_pretty_string(2), expected);
+        assert_eq!(format!("{:#}", tree), expected);
+    }
+
+    #[test]
+    fn build_trees_without_parsing() {
+        let tree = Tree::element(
+            "Doc",
+            vec![(String::from("title"), "Built".into())],
+            vec![
+                Tree::element("Title", vec![], vec![Tree::text("Hello")]),
+                Tree::text("world"),
+            ],
+        );
+
+        assert_eq!(
+            tree.to_string(),
+            r#"<Doc title="Built"><Title>Hello</Title>world</Doc>"#
+        );
+    }
 }
@@ -0,0 +1,49 @@
+use hyli::{parse_reader, parse_str, Tree};
+
+#[test]
+fn parse_valid_document() {
+    let (tree, errors) = parse_str(r#"<Doc><Title lang="en">Hi</Title></Doc>"#);
+
+    assert!(errors.is_empty());
+    match tree {
+        Some(Tree::Inner { tag_name, .. }) => assert_eq!(tag_name, "Doc"),
+        tree => panic!("unexpected tree: {:?}", tree),
+    }
+}
+
+#[test]
+fn parse_invalid_document() {
+    let (tree, errors) = parse_str("<Doc><Title></Doc>");
+
+    assert!(tree.is_none());
+    assert!(!errors.is_empty());
+    assert!(errors[0].to_string().contains("<Doc><Title></Doc>"));
+}
+
+#[test]
+fn errors_keep_their_own_source() {
+    let (_, first) = parse_str("<Doc><Title></Doc>");
+    let (_, second) = parse_str("<Other><Item></Other>");
+
+    assert!(first[0].to_string().contains("<Doc><Title></Doc>"));
+    assert!(second[0].to_string().contains("<Other><Item></Other>"));
+}
+
+#[test]
+fn parse_from_reader() {
+    let input: &[u8] = br#"<Doc><Title lang="en">Hi</Title></Doc>"#;
+    let (tree, errors) = parse_reader(input).unwrap();
+
+    assert!(errors.is_empty());
+    match tree {
+        Some(Tree::Inner { tag_name, .. }) => assert_eq!(tag_name, "Doc"),
+        tree => panic!("unexpected tree: {:?}", tree),
+    }
+}
+
+#[test]
+fn parse_reader_rejects_invalid_utf8() {
+    let input: &[u8] = b"<Doc>\xff</Doc>";
+
+    assert!(parse_reader(input).is_err());
+}
@@ -0,0 +1,64 @@
+// Compares the peak memory use of parsing directly into a `Tree` with that of
+// building the syntax tree first. This is its own test binary because it
+// replaces the global allocator.
+
+use hyli::{parse_str, parse_to_tree, ParseOptions};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+// Counts the bytes allocated by the current thread.
+struct CountingAlloc;
+
+thread_local! {
+    static CURRENT: Cell<usize> = const { Cell::new(0) };
+    static PEAK: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = CURRENT.try_with(|current| {
+            current.set(current.get() + layout.size());
+            let _ = PEAK.try_with(|peak| peak.set(peak.get().max(current.get())));
+        });
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ =
+            CURRENT.try_with(|current| current.set(current.get().saturating_sub(layout.size())));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+// Returns the result of `f` and the peak number of bytes allocated while
+// running it, beyond those allocated beforehand.
+fn peak_during<T, F>(f: F) -> (T, usize)
+where
+    F: FnOnce() -> T,
+{
+    let base = CURRENT.with(|current| current.get());
+    PEAK.with(|peak| peak.set(base));
+    let result = f();
+    (result, PEAK.with(|peak| peak.get()) - base)
+}
+
+#[test]
+fn parse_to_tree_uses_less_memory() {
+    let sections = "\n  <Section><Mono>x</Mono> &amp; y</Section>".repeat(200);
+    let src = format!("<Doc>{}\n</Doc>", sections);
+    let src = src.as_str();
+
+    let (direct, direct_peak) = peak_during(|| parse_to_tree(src, &ParseOptions::new()).unwrap());
+    let (two_pass, two_pass_peak) = peak_during(|| parse_str(src).0.unwrap());
+
+    assert_eq!(direct.to_string(), two_pass.to_string());
+    assert!(
+        direct_peak < two_pass_peak,
+        "direct: {} bytes, two-pass: {} bytes",
+        direct_peak,
+        two_pass_peak
+    );
+}
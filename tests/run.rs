@@ -0,0 +1,52 @@
+use hyli::{run, run_check, ComponentSchema, Processor, SyntaxErrors, ValidationErrors};
+use std::env;
+use std::fs;
+
+#[test]
+fn run_returns_syntax_errors() {
+    let path = env::temp_dir().join("hyli-run-syntax-errors.xml");
+    fs::write(&path, "<Doc>\n  <Title>hi\n</Doc>").unwrap();
+
+    let error = run(path.to_str().unwrap(), &Processor::new()).unwrap_err();
+    fs::remove_file(&path).unwrap();
+
+    let errors = &error.downcast_ref::<SyntaxErrors>().unwrap().0;
+    assert!(!errors.is_empty());
+    assert!(errors[0]
+        .message
+        .starts_with("closing tag must match opening"));
+    assert!(error.to_string().contains("</Doc>"));
+}
+
+#[test]
+fn run_returns_validation_errors() {
+    let path = env::temp_dir().join("hyli-run-validation-errors.xml");
+    fs::write(&path, "<Doc>\n  <Title>hi</Title>\n</Doc>").unwrap();
+
+    let mut proc = Processor::new();
+    let mut title = ComponentSchema::new();
+    title.require_attr("level");
+    proc.add_schema("Title", title);
+
+    let error = run(path.to_str().unwrap(), &proc).unwrap_err();
+    fs::remove_file(&path).unwrap();
+
+    let errors = &error.downcast_ref::<ValidationErrors>().unwrap().0;
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].message, "Title requires attribute 'level'");
+    assert!(error.to_string().contains("<Title>hi</Title>"));
+}
+
+#[test]
+fn run_check_reports_only_syntax_errors() {
+    let path = env::temp_dir().join("hyli-run-check.xml");
+
+    fs::write(&path, "<Doc><Title>hi</Title></Doc>").unwrap();
+    assert!(run_check(path.to_str().unwrap()).is_ok());
+
+    fs::write(&path, "<Doc>\n  <Title>hi\n</Doc>").unwrap();
+    let error = run_check(path.to_str().unwrap()).unwrap_err();
+    fs::remove_file(&path).unwrap();
+
+    assert!(error.downcast_ref::<SyntaxErrors>().is_some());
+}
@@ -0,0 +1,33 @@
+use hyli::{parse_to_tree, AttrLengthPolicy, ParseOptions, Span, SyntaxError};
+
+#[test]
+fn mismatched_close_tag() {
+    let src = "<Doc><Title>hi</Heading></Doc>";
+
+    let errors = parse_to_tree(src, &ParseOptions::new()).unwrap_err();
+
+    assert_eq!(
+        errors,
+        vec![SyntaxError::new(
+            Span::new(16, 23),
+            r#"closing tag must match opening (expected "Title" but found "Heading")"#,
+        )]
+    );
+}
+
+#[test]
+fn overlong_attr_value() {
+    let src = r#"<Img alt="café au lait"></Img>"#;
+    let mut options = ParseOptions::new();
+    options.set_max_attr_value_len(4, AttrLengthPolicy::Error);
+
+    let errors = parse_to_tree(src, &options).unwrap_err();
+
+    assert_eq!(
+        errors,
+        vec![SyntaxError::new(
+            Span::new(5, 23),
+            "attribute value is longer than 4 bytes"
+        )]
+    );
+}